@@ -0,0 +1,379 @@
+use crate::codec::{self, Encoding};
+use crate::icc;
+use crate::image_kernel::{self, encoding_error, BitDepth, ImageKernel};
+use crate::metadata::Metadata;
+use crate::palette::{Dither, Palette};
+use crate::pipeline::Pipeline;
+use image::codecs::gif::{GifDecoder, Repeat};
+use image::{AnimationDecoder, Pixel};
+use std::convert::TryFrom;
+use std::io::Write;
+
+/// Frame of an animation, composited to the full canvas
+#[derive(Clone)]
+pub struct Frame {
+    /// RGBA at 8 bits, or at 16 for 16 bit APNG
+    pub image: image::DynamicImage,
+    pub delay: image::Delay,
+}
+
+/// Frames of an animation, each one composited to the full canvas
+pub struct Animation {
+    pub frames: Vec<Frame>,
+    pub repeat: Repeat,
+}
+
+fn decoding_error<E>(error: E) -> image::ImageError
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    image::ImageError::Decoding(image::error::DecodingError::new(
+        image::ImageFormat::Png.into(),
+        error,
+    ))
+}
+
+/// Alpha channel of an RGBA `image` as grayscale of the same depth
+fn alpha_channel(image: &image::DynamicImage) -> image::DynamicImage {
+    match image {
+        image::DynamicImage::ImageRgba16(buffer) => image::DynamicImage::ImageLuma16(
+            image::ImageBuffer::from_fn(buffer.width(), buffer.height(), |x, y| {
+                image::Luma([buffer.get_pixel(x, y)[3]])
+            }),
+        ),
+        image => {
+            let buffer = image.to_rgba8();
+            image::DynamicImage::ImageLuma8(image::ImageBuffer::from_fn(
+                buffer.width(),
+                buffer.height(),
+                |x, y| image::Luma([buffer.get_pixel(x, y)[3]]),
+            ))
+        }
+    }
+}
+
+impl Animation {
+    /// Decodes every frame of a GIF, composited according to its disposal method
+    pub fn read_gif(encoded: &[u8]) -> image::ImageResult<Animation> {
+        let repeat = match gif::DecodeOptions::new()
+            .read_info(encoded)
+            .map(|decoder| decoder.repeat())
+        {
+            Ok(gif::Repeat::Finite(count)) => Repeat::Finite(count),
+            _ => Repeat::Infinite,
+        };
+        let frames = GifDecoder::new(encoded)?
+            .into_frames()
+            .map(|frame| {
+                frame.map(|frame| Frame {
+                    delay: frame.delay(),
+                    image: image::DynamicImage::ImageRgba8(frame.into_buffer()),
+                })
+            })
+            .collect::<image::ImageResult<_>>()?;
+        Ok(Animation { frames, repeat })
+    }
+
+    /// Decodes every frame of an APNG, composited according to its blend and
+    /// dispose ops. 16 bit frames stay 16 bit, image only decodes 8 bit APNG.
+    pub fn read_apng(encoded: &[u8]) -> image::ImageResult<Animation> {
+        let mut decoder = png::Decoder::new(encoded);
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().map_err(decoding_error)?;
+        let info = reader.info();
+        let (width, height) = info.size();
+        let (frame_count, repeat) = match info.animation_control {
+            Some(control) if control.num_plays > 0 => {
+                (control.num_frames, Repeat::Finite(control.num_plays as u16))
+            }
+            Some(control) => (control.num_frames, Repeat::Infinite),
+            None => (0, Repeat::Infinite),
+        };
+        // A default image without an fcTL isn't part of the animation
+        let mut thumbnail = info.frame_control.is_none();
+        let deep = info.bit_depth == png::BitDepth::Sixteen;
+
+        // Composited at 16 bits, which 8 bit values convert to and from exactly
+        let mut canvas = image::ImageBuffer::<image::Rgba<u16>, _>::new(width, height);
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let mut frames = Vec::with_capacity(frame_count as usize);
+        while frames.len() < frame_count as usize {
+            let output = reader.next_frame(&mut buffer).map_err(decoding_error)?;
+            if thumbnail {
+                thumbnail = false;
+                continue;
+            }
+            let control = match reader.info().frame_control() {
+                Some(control) => *control,
+                None => break,
+            };
+
+            if control.x_offset + output.width > width || control.y_offset + output.height > height
+            {
+                return Err(decoding_error("APNG frame lies outside of the canvas"));
+            }
+            // Every sample at 16 bits, gray and missing alpha expanded to RGBA
+            let raw = &buffer[..output.buffer_size()];
+            let samples: Vec<u16> = match output.bit_depth {
+                png::BitDepth::Sixteen => raw
+                    .chunks_exact(2)
+                    .map(|sample| u16::from_be_bytes([sample[0], sample[1]]))
+                    .collect(),
+                _ => raw.iter().map(|&sample| sample as u16 * 257).collect(),
+            };
+            let channels = output.color_type.samples();
+            let frame = samples.chunks_exact(channels).map(|pixel| match *pixel {
+                [gray] => image::Rgba([gray, gray, gray, u16::MAX]),
+                [gray, alpha] => image::Rgba([gray, gray, gray, alpha]),
+                [r, g, b] => image::Rgba([r, g, b, u16::MAX]),
+                [r, g, b, alpha, ..] => image::Rgba([r, g, b, alpha]),
+                _ => image::Rgba([0, 0, 0, 0]),
+            });
+
+            let previous = match control.dispose_op {
+                png::DisposeOp::Previous => Some(canvas.clone()),
+                _ => None,
+            };
+            for (i, pixel) in frame.enumerate() {
+                let x = control.x_offset + i as u32 % output.width;
+                let y = control.y_offset + i as u32 / output.width;
+                let target = canvas.get_pixel_mut(x, y);
+                match control.blend_op {
+                    png::BlendOp::Source => *target = pixel,
+                    png::BlendOp::Over => target.blend(&pixel),
+                }
+            }
+
+            // PNG delays are a fraction of a second, a denominator of 0
+            // standing for 100
+            let denom = match control.delay_den {
+                0 => 100,
+                denom => denom as u32,
+            };
+            let image = if deep {
+                image::DynamicImage::ImageRgba16(canvas.clone())
+            } else {
+                image::DynamicImage::ImageRgba8(
+                    image::DynamicImage::ImageRgba16(canvas.clone()).into_rgba8(),
+                )
+            };
+            frames.push(Frame {
+                image,
+                delay: image::Delay::from_numer_denom_ms(control.delay_num as u32 * 1000, denom),
+            });
+
+            match (control.dispose_op, previous) {
+                (png::DisposeOp::Background, _) => {
+                    for y in control.y_offset..control.y_offset + output.height {
+                        for x in control.x_offset..control.x_offset + output.width {
+                            canvas.put_pixel(x, y, image::Rgba([0, 0, 0, 0]));
+                        }
+                    }
+                }
+                (png::DisposeOp::Previous, Some(previous)) => canvas = previous,
+                _ => {}
+            }
+        }
+        Ok(Animation { frames, repeat })
+    }
+
+    /// Encodes an APNG, at 16 bits when the frames are. The frames are
+    /// already composited, so each one covers the whole canvas and replaces
+    /// the previous one. `icc_profile` and `metadata` are embedded like in a
+    /// still PNG, and `encoding` sets the compression.
+    pub fn write_apng(
+        &self,
+        filename: &str,
+        icc_profile: Option<&[u8]>,
+        metadata: &Metadata,
+        encoding: &Encoding,
+    ) -> image::ImageResult<()> {
+        let error = |e| encoding_error(image::ImageFormat::Png, e);
+        let (width, height) = self
+            .frames
+            .first()
+            .map_or((0, 0), |frame| (frame.image.width(), frame.image.height()));
+        let deep = self
+            .frames
+            .iter()
+            .any(|frame| matches!(frame.image, image::DynamicImage::ImageRgba16(_)));
+        let mut encoded = Vec::new();
+        let mut encoder = png::Encoder::new(&mut encoded, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(if deep {
+            png::BitDepth::Sixteen
+        } else {
+            png::BitDepth::Eight
+        });
+        codec::configure_png(&mut encoder, encoding);
+        let plays = match self.repeat {
+            Repeat::Finite(count) => count as u32,
+            Repeat::Infinite => 0,
+        };
+        encoder
+            .set_animated(self.frames.len() as u32, plays)
+            .map_err(error)?;
+
+        let mut writer = encoder.write_header().map_err(error)?;
+        for frame in &self.frames {
+            let (numer, denom) = apng_delay(frame.delay);
+            writer.set_frame_delay(numer, denom).map_err(error)?;
+            let data = if deep {
+                frame
+                    .image
+                    .to_rgba16()
+                    .iter()
+                    .flat_map(|sample| sample.to_be_bytes())
+                    .collect()
+            } else {
+                frame.image.to_rgba8().into_raw()
+            };
+            writer.write_image_data(&data).map_err(error)?;
+        }
+        writer.finish().map_err(error)?;
+
+        if let Some(profile) = icc_profile {
+            encoded = icc::embed(encoded, profile);
+        }
+        let mut output = image_kernel::create(filename)?;
+        output.write_all(&metadata.embed(encoded, (width, height)))?;
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Encodes a GIF, every frame getting a palette of its own. GIF
+    /// transparency is on or off and takes one of the 256 entries, so alpha is
+    /// cut at half.
+    pub fn write_gif(&self, filename: &str, dither: Dither) -> image::ImageResult<()> {
+        let error = |e| encoding_error(image::ImageFormat::Gif, e);
+        let (width, height) = self
+            .frames
+            .first()
+            .map_or((0, 0), |frame| (frame.image.width(), frame.image.height()));
+        let (width, height) = match (u16::try_from(width), u16::try_from(height)) {
+            (Ok(width), Ok(height)) => (width, height),
+            _ => {
+                return Err(encoding_error(
+                    image::ImageFormat::Gif,
+                    "GIF can't be larger than 65535 pixels per side",
+                ))
+            }
+        };
+        let writer = image_kernel::create(filename)?;
+        let mut encoder = gif::Encoder::new(writer, width, height, &[]).map_err(error)?;
+        encoder
+            .set_repeat(match self.repeat {
+                Repeat::Finite(count) => gif::Repeat::Finite(count),
+                Repeat::Infinite => gif::Repeat::Infinite,
+            })
+            .map_err(error)?;
+
+        for frame in &self.frames {
+            let buffer = &frame.image.to_rgba8();
+            let opaque: Vec<u8> = buffer
+                .pixels()
+                .filter(|pixel| pixel[3] >= 0x80)
+                .flat_map(|pixel| vec![pixel[0], pixel[1], pixel[2], 0xFF])
+                .collect();
+            let mut palette = match image::RgbaImage::from_raw(opaque.len() as u32 / 4, 1, opaque) {
+                Some(opaque) if opaque.width() > 0 => Palette::generate(&opaque, 255),
+                _ => Palette {
+                    colors: vec![[0, 0, 0, 0xFF]],
+                },
+            };
+
+            let mut indices = palette.quantize(buffer, dither);
+            let transparent = palette.colors.len() as u8;
+            palette.colors.push([0, 0, 0, 0]);
+            for (index, pixel) in indices.iter_mut().zip(buffer.pixels()) {
+                if pixel[3] < 0x80 {
+                    *index = transparent;
+                }
+            }
+
+            let rgb: Vec<u8> = palette
+                .colors
+                .iter()
+                .flat_map(|color| color[..3].to_vec())
+                .collect();
+            let mut gif_frame =
+                gif::Frame::from_palette_pixels(width, height, indices, rgb, Some(transparent));
+            // GIF counts in hundredths of a second
+            let (numer, denom) = frame.delay.numer_denom_ms();
+            gif_frame.delay = (numer as f64 / denom as f64 / 10.0).round() as u16;
+            gif_frame.dispose = gif::DisposalMethod::Background;
+            encoder.write_frame(&gif_frame).map_err(error)?;
+        }
+        Ok(())
+    }
+
+    /// Runs `pipeline` on every frame with `kernel`, which carries the
+    /// settings from one frame to the next, keeping the delays and the bit
+    /// depth. The passes use the alpha channel as scratch, so it is scaled on
+    /// its own and put back. Frames are converted to `target_profile` when
+    /// given, which `kernel` then holds.
+    pub fn upscale(
+        &self,
+        kernel: &mut ImageKernel,
+        pipeline: &Pipeline,
+        target_profile: Option<&[u8]>,
+    ) -> Result<Animation, String> {
+        let icc_profile = kernel.icc_profile.clone();
+        let mut frames = Vec::with_capacity(self.frames.len());
+        for frame in &self.frames {
+            kernel.load(frame.image.clone());
+            kernel.icc_profile = icc_profile.clone();
+            pipeline.run(kernel);
+            if let Some(target_profile) = target_profile {
+                kernel.convert_profile(target_profile)?;
+            }
+
+            let mut mask = ImageKernel::from_luma_image(alpha_channel(&frame.image));
+            mask.quantization = kernel.quantization;
+            pipeline.scale_mask(&mut mask);
+
+            let image = if kernel.bit_depth == BitDepth::Sixteen {
+                let mut buffer = kernel.to_rgba16();
+                for (pixel, alpha) in buffer.pixels_mut().zip(mask.to_luma16().pixels()) {
+                    pixel[3] = alpha[0];
+                }
+                image::DynamicImage::ImageRgba16(buffer)
+            } else {
+                let mut buffer = kernel.to_rgba8();
+                for (pixel, alpha) in buffer.pixels_mut().zip(mask.to_luma8().pixels()) {
+                    pixel[3] = alpha[0];
+                }
+                image::DynamicImage::ImageRgba8(buffer)
+            };
+            frames.push(Frame {
+                image,
+                delay: frame.delay,
+            });
+        }
+
+        Ok(Animation {
+            frames,
+            repeat: self.repeat,
+        })
+    }
+}
+
+/// `fcTL` delays are a fraction of a second with 16 bit terms. Delays that
+/// don't fit are rounded to the millisecond.
+fn apng_delay(delay: image::Delay) -> (u16, u16) {
+    let (numer, denom) = delay.numer_denom_ms();
+    let (numer, denom) = (numer as u64, denom as u64 * 1000);
+    let (mut a, mut b) = (numer, denom);
+    while b != 0 {
+        let rest = a % b;
+        a = b;
+        b = rest;
+    }
+    let (numer, denom) = (numer / a, denom / a);
+    if numer <= u16::MAX as u64 && denom <= u16::MAX as u64 {
+        (numer as u16, denom as u16)
+    } else {
+        let ms = (numer as f64 * 1000.0 / denom as f64).round();
+        (ms.min(u16::MAX as f64) as u16, 1000)
+    }
+}
@@ -0,0 +1,293 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// How much the JPEG chroma planes are reduced
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChromaSubsampling {
+    /// Full resolution chroma
+    Yuv444,
+    /// Half the horizontal resolution
+    Yuv422,
+    /// Half the resolution both ways
+    Yuv420,
+}
+
+impl FromStr for ChromaSubsampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ChromaSubsampling, String> {
+        match s {
+            "444" => Ok(ChromaSubsampling::Yuv444),
+            "422" => Ok(ChromaSubsampling::Yuv422),
+            "420" => Ok(ChromaSubsampling::Yuv420),
+            _ => Err(format!("Unknown subsampling {}", s)),
+        }
+    }
+}
+
+/// How hard the PNG encoder compresses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+impl FromStr for PngCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PngCompression, String> {
+        match s {
+            "fast" => Ok(PngCompression::Fast),
+            "default" => Ok(PngCompression::Default),
+            "best" => Ok(PngCompression::Best),
+            _ => Err(format!("Unknown PNG compression {}", s)),
+        }
+    }
+}
+
+/// The PNG row filter, `Adaptive` picking one per row
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    Adaptive,
+}
+
+impl FromStr for PngFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PngFilter, String> {
+        match s {
+            "none" => Ok(PngFilter::None),
+            "sub" => Ok(PngFilter::Sub),
+            "up" => Ok(PngFilter::Up),
+            "average" => Ok(PngFilter::Average),
+            "paeth" => Ok(PngFilter::Paeth),
+            "adaptive" => Ok(PngFilter::Adaptive),
+            _ => Err(format!("Unknown PNG filter {}", s)),
+        }
+    }
+}
+
+/// Settings of the format encoders
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Encoding {
+    /// WebP and AVIF quality, 0 for the smallest file up to 100 for the best
+    pub quality: f32,
+    /// Writes WebP without any loss
+    pub lossless: bool,
+    /// AVIF encoder speed, 1 being the slowest and smallest up to 10
+    pub avif_speed: u8,
+    /// WebP compression method, 0 being the fastest up to 6
+    pub webp_method: i32,
+    /// JPEG quality from 1 to 100
+    pub jpeg_quality: u8,
+    pub jpeg_subsampling: ChromaSubsampling,
+    pub jpeg_progressive: bool,
+    pub png_compression: PngCompression,
+    pub png_filter: PngFilter,
+    /// Color that transparent pixels are laid on for formats without alpha
+    pub background: image::Rgba<u8>,
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding {
+            quality: 90.0,
+            lossless: false,
+            avif_speed: 6,
+            webp_method: 4,
+            jpeg_quality: 90,
+            jpeg_subsampling: ChromaSubsampling::Yuv420,
+            jpeg_progressive: false,
+            png_compression: PngCompression::Default,
+            png_filter: PngFilter::Adaptive,
+            background: image::Rgba([0xFF, 0xFF, 0xFF, 0xFF]),
+        }
+    }
+}
+
+/// Whether `format` can store an alpha channel. Of the PNM family only PAM can.
+pub fn has_alpha(filename: &str, format: image::ImageFormat) -> bool {
+    match format {
+        image::ImageFormat::Jpeg => false,
+        image::ImageFormat::Pnm => filename.to_lowercase().ends_with(".pam"),
+        _ => true,
+    }
+}
+
+/// Lays `image` over `background` and drops the alpha channel, keeping the
+/// precision and whether it is grayscale
+pub fn flatten(image: image::DynamicImage, background: image::Rgba<u8>) -> image::DynamicImage {
+    if !image.color().has_alpha() {
+        return image;
+    }
+    let color = image.color();
+    let mut flat = image.into_rgba32f();
+    for pixel in flat.pixels_mut() {
+        let alpha = pixel[3];
+        for (value, &under) in pixel.0.iter_mut().zip(background.0.iter()).take(3) {
+            *value = *value * alpha + under as f32 / 255.0 * (1.0 - alpha);
+        }
+        pixel[3] = 1.0;
+    }
+    let flat = image::DynamicImage::ImageRgba32F(flat);
+    match color {
+        image::ColorType::La8 => image::DynamicImage::ImageLuma8(flat.to_luma8()),
+        image::ColorType::La16 => image::DynamicImage::ImageLuma16(flat.to_luma16()),
+        image::ColorType::Rgba16 => image::DynamicImage::ImageRgb16(flat.to_rgb16()),
+        image::ColorType::Rgba32F => image::DynamicImage::ImageRgb32F(flat.to_rgb32f()),
+        _ => image::DynamicImage::ImageRgb8(flat.to_rgb8()),
+    }
+}
+
+/// Encodes `image` as 8 bit baseline or progressive JPEG
+pub fn jpeg(image: &image::DynamicImage, encoding: &Encoding) -> Result<Vec<u8>, String> {
+    let error = |e| format!("Can't encode JPEG: {}", e);
+    let mut encoded = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut encoded, encoding.jpeg_quality);
+    encoder.set_sampling_factor(match encoding.jpeg_subsampling {
+        ChromaSubsampling::Yuv444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+        ChromaSubsampling::Yuv422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+        ChromaSubsampling::Yuv420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+    });
+    encoder.set_progressive(encoding.jpeg_progressive);
+    let (width, height) = match (u16::try_from(image.width()), u16::try_from(image.height())) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => return Err("JPEG can't be larger than 65535 pixels per side".to_string()),
+    };
+    if image.color().has_color() {
+        encoder.encode(
+            &image.to_rgb8(),
+            width,
+            height,
+            jpeg_encoder::ColorType::Rgb,
+        )
+    } else {
+        encoder.encode(
+            &image.to_luma8(),
+            width,
+            height,
+            jpeg_encoder::ColorType::Luma,
+        )
+    }
+    .map_err(error)?;
+    Ok(encoded)
+}
+
+/// Encodes `image` as PNG with the chosen compression and row filter
+pub fn png(image: &image::DynamicImage, encoding: &Encoding) -> image::ImageResult<Vec<u8>> {
+    use image::codecs::png::{CompressionType, FilterType};
+    use image::ImageEncoder;
+
+    let compression = match encoding.png_compression {
+        PngCompression::Fast => CompressionType::Fast,
+        PngCompression::Default => CompressionType::Default,
+        PngCompression::Best => CompressionType::Best,
+    };
+    let filter = match encoding.png_filter {
+        PngFilter::None => FilterType::NoFilter,
+        PngFilter::Sub => FilterType::Sub,
+        PngFilter::Up => FilterType::Up,
+        PngFilter::Average => FilterType::Avg,
+        PngFilter::Paeth => FilterType::Paeth,
+        PngFilter::Adaptive => FilterType::Adaptive,
+    };
+    let mut encoded = Vec::new();
+    image::codecs::png::PngEncoder::new_with_quality(&mut encoded, compression, filter)
+        .write_image(
+            image.as_bytes(),
+            image.width(),
+            image.height(),
+            image.color(),
+        )?;
+    Ok(encoded)
+}
+
+/// Applies the compression and the row filter of `encoding` to a `png` crate
+/// encoder, which APNG is written with
+pub fn configure_png<W: std::io::Write>(encoder: &mut png::Encoder<W>, encoding: &Encoding) {
+    encoder.set_compression(match encoding.png_compression {
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Default,
+        PngCompression::Best => png::Compression::Best,
+    });
+    let (filter, adaptive) = match encoding.png_filter {
+        PngFilter::None => (
+            png::FilterType::NoFilter,
+            png::AdaptiveFilterType::NonAdaptive,
+        ),
+        PngFilter::Sub => (png::FilterType::Sub, png::AdaptiveFilterType::NonAdaptive),
+        PngFilter::Up => (png::FilterType::Up, png::AdaptiveFilterType::NonAdaptive),
+        PngFilter::Average => (png::FilterType::Avg, png::AdaptiveFilterType::NonAdaptive),
+        PngFilter::Paeth => (png::FilterType::Paeth, png::AdaptiveFilterType::NonAdaptive),
+        PngFilter::Adaptive => (png::FilterType::Sub, png::AdaptiveFilterType::Adaptive),
+    };
+    encoder.set_filter(filter);
+    encoder.set_adaptive_filter(adaptive);
+}
+
+/// Encodes `image` as lossy or lossless WebP
+pub fn webp(image: &image::RgbaImage, encoding: &Encoding) -> Result<Vec<u8>, String> {
+    let mut config = webp::WebPConfig::new().map_err(|_| "Can't set up the WebP encoder")?;
+    config.lossless = encoding.lossless as i32;
+    config.quality = encoding.quality;
+    config.method = encoding.webp_method;
+    // Lossless keeps the alpha values as they are, and the color under
+    // fully transparent pixels too
+    config.alpha_compression = !encoding.lossless as i32;
+    config.exact = encoding.lossless as i32;
+    webp::Encoder::from_rgba(image.as_raw(), image.width(), image.height())
+        .encode_advanced(&config)
+        .map(|encoded| encoded.to_vec())
+        .map_err(|e| format!("Can't encode WebP: {:?}", e))
+}
+
+/// Encodes `image` as AVIF, which has no lossless mode
+pub fn avif(image: &image::RgbaImage, encoding: &Encoding) -> Result<Vec<u8>, String> {
+    if encoding.lossless {
+        return Err("AVIF can't be written losslessly, use WebP or PNG".to_string());
+    }
+    let pixels: Vec<ravif::RGBA8> = image
+        .pixels()
+        .map(|pixel| ravif::RGBA8::new(pixel[0], pixel[1], pixel[2], pixel[3]))
+        .collect();
+    ravif::Encoder::new()
+        .with_quality(encoding.quality)
+        .with_alpha_quality(encoding.quality)
+        .with_speed(encoding.avif_speed)
+        .encode_rgba(ravif::Img::new(
+            &pixels[..],
+            image.width() as usize,
+            image.height() as usize,
+        ))
+        .map(|encoded| encoded.avif_file)
+        .map_err(|e| format!("Can't encode AVIF: {}", e))
+}
+
+/// Encodes `image` as binary PPM, or PGM when it is grayscale. It has to be
+/// opaque already.
+pub fn pnm(image: &image::DynamicImage) -> image::ImageResult<Vec<u8>> {
+    use image::codecs::pnm::{PnmEncoder, PnmSubtype, SampleEncoding};
+    use image::ImageEncoder;
+
+    let subtype = if image.color().has_color() {
+        PnmSubtype::Pixmap(SampleEncoding::Binary)
+    } else {
+        PnmSubtype::Graymap(SampleEncoding::Binary)
+    };
+    let mut encoded = Vec::new();
+    PnmEncoder::new(&mut encoded)
+        .with_subtype(subtype)
+        .write_image(
+            image.as_bytes(),
+            image.width(),
+            image.height(),
+            image.color(),
+        )?;
+    Ok(encoded)
+}
@@ -0,0 +1,85 @@
+fn decoding_error(message: String) -> image::ImageError {
+    image::ImageError::Decoding(image::error::DecodingError::new(
+        image::ImageFormat::Avif.into(),
+        message,
+    ))
+}
+
+/// Decodes the primary image of an AVIF (or HEIF) file with libheif. Images
+/// deeper than 8 bits come out as 16 bit.
+#[cfg(feature = "avif-decode")]
+pub fn decode(encoded: &[u8]) -> image::ImageResult<image::DynamicImage> {
+    decode_with_libheif(encoded).map_err(decoding_error)
+}
+
+/// Reading AVIF needs libheif, which this build is made without
+#[cfg(not(feature = "avif-decode"))]
+pub fn decode(_encoded: &[u8]) -> image::ImageResult<image::DynamicImage> {
+    Err(decoding_error(
+        "Reading AVIF needs a build with the avif-decode feature".to_string(),
+    ))
+}
+
+#[cfg(feature = "avif-decode")]
+fn decode_with_libheif(encoded: &[u8]) -> Result<image::DynamicImage, String> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let library = LibHeif::new();
+    let context = HeifContext::read_from_bytes(encoded).map_err(|e| e.to_string())?;
+    let handle = context.primary_image_handle().map_err(|e| e.to_string())?;
+    let alpha = handle.has_alpha_channel();
+    let bits = handle.luma_bits_per_pixel();
+    if bits > 16 {
+        return Err(format!("{} bit AVIF isn't supported", bits));
+    }
+    let deep = bits > 8;
+    let chroma = match (deep, alpha) {
+        (false, false) => RgbChroma::Rgb,
+        (false, true) => RgbChroma::Rgba,
+        (true, false) => RgbChroma::HdrRgbLe,
+        (true, true) => RgbChroma::HdrRgbaLe,
+    };
+    let decoded = library
+        .decode(&handle, ColorSpace::Rgb(chroma), None)
+        .map_err(|e| e.to_string())?;
+
+    let plane = decoded
+        .planes()
+        .interleaved
+        .ok_or_else(|| "libheif returned no pixels".to_string())?;
+    let (width, height) = (plane.width, plane.height);
+    let channels = if alpha { 4 } else { 3 };
+    let row = width as usize * channels * if deep { 2 } else { 1 };
+    if plane.stride < row {
+        return Err("libheif returned a short plane".to_string());
+    }
+    let raw: Vec<u8> = plane
+        .data
+        .chunks(plane.stride)
+        .take(height as usize)
+        .flat_map(|line| &line[..row])
+        .copied()
+        .collect();
+
+    let image = if deep {
+        // Samples are little endian at the bit depth of the file
+        let max = (1u32 << bits) - 1;
+        let raw: Vec<u16> = raw
+            .chunks_exact(2)
+            .map(|sample| {
+                let value = u16::from_le_bytes([sample[0], sample[1]]) as u32;
+                ((value.min(max) * 0xFFFF + max / 2) / max) as u16
+            })
+            .collect();
+        if alpha {
+            image::ImageBuffer::from_raw(width, height, raw).map(image::DynamicImage::ImageRgba16)
+        } else {
+            image::ImageBuffer::from_raw(width, height, raw).map(image::DynamicImage::ImageRgb16)
+        }
+    } else if alpha {
+        image::ImageBuffer::from_raw(width, height, raw).map(image::DynamicImage::ImageRgba8)
+    } else {
+        image::ImageBuffer::from_raw(width, height, raw).map(image::DynamicImage::ImageRgb8)
+    };
+    image.ok_or_else(|| "libheif returned a short plane".to_string())
+}
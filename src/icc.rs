@@ -0,0 +1,63 @@
+use img_parts::{Bytes, DynImage, ImageICC};
+
+/// Reads the ICC profile embedded in an encoded PNG, JPEG or WebP file
+pub fn extract(encoded: &[u8]) -> Option<Vec<u8>> {
+    match DynImage::from_bytes(Bytes::copy_from_slice(encoded)) {
+        Ok(Some(image)) => image.icc_profile().map(|profile| profile.to_vec()),
+        _ => None,
+    }
+}
+
+/// Embeds `profile` into an encoded PNG, JPEG or WebP file. Other formats
+/// can't carry one and are returned untouched.
+pub fn embed(encoded: Vec<u8>, profile: &[u8]) -> Vec<u8> {
+    match DynImage::from_bytes(Bytes::from(encoded.clone())) {
+        Ok(Some(mut image)) => {
+            image.set_icc_profile(Some(Bytes::copy_from_slice(profile)));
+            image.encoder().bytes().to_vec()
+        }
+        _ => encoded,
+    }
+}
+
+/// Returns the profile named `srgb`, `display-p3`, `adobe-rgb` or `bt2020`,
+/// anything else is read as the path of an ICC file
+pub fn load(name: &str) -> Result<Vec<u8>, String> {
+    let profile = match name {
+        "srgb" => moxcms::ColorProfile::new_srgb(),
+        "display-p3" => moxcms::ColorProfile::new_display_p3(),
+        "adobe-rgb" => moxcms::ColorProfile::new_adobe_rgb(),
+        "bt2020" => moxcms::ColorProfile::new_bt2020(),
+        path => {
+            return std::fs::read(path).map_err(|e| format!("Can't read profile {}: {}", path, e))
+        }
+    };
+    profile
+        .encode()
+        .map_err(|e| format!("Can't encode profile {}: {}", name, e))
+}
+
+/// Converts interleaved RGBA values from the `source` profile to `target`,
+/// an untagged source being taken as sRGB
+pub fn convert(data: &mut [f32], source: Option<&[u8]>, target: &[u8]) -> Result<(), String> {
+    let source = match source {
+        Some(source) => moxcms::ColorProfile::new_from_slice(source)
+            .map_err(|e| format!("Invalid source profile: {}", e))?,
+        None => moxcms::ColorProfile::new_srgb(),
+    };
+    let target = moxcms::ColorProfile::new_from_slice(target)
+        .map_err(|e| format!("Invalid target profile: {}", e))?;
+    let transform = source
+        .create_transform_f32(
+            moxcms::Layout::Rgba,
+            &target,
+            moxcms::Layout::Rgba,
+            moxcms::TransformOptions::default(),
+        )
+        .map_err(|e| format!("Can't convert between the profiles: {}", e))?;
+
+    let source_data = data.to_vec();
+    transform
+        .transform(&source_data, data)
+        .map_err(|e| format!("Can't convert between the profiles: {}", e))
+}
@@ -1,4 +1,12 @@
-use std::cmp::{max, min, PartialOrd};
+use crate::animation::Animation;
+use crate::codec::{self, Encoding};
+use crate::heif;
+use crate::icc;
+use crate::metadata::Metadata;
+use crate::palette::{bayer, Dither, Palette};
+use crate::raw::{self, RawFormat};
+use std::io::{Read, Write};
+use std::str::FromStr;
 
 pub fn clamp<T: PartialOrd>(val: T, min: T, max: T) -> T {
     if val < min {
@@ -11,80 +19,988 @@ pub fn clamp<T: PartialOrd>(val: T, min: T, max: T) -> T {
 }
 
 #[inline]
-pub fn extract_pixel_rgba(pixel: image::Rgba<u8>) -> (u8, u8, u8, u8) {
-    (pixel[0], pixel[1], pixel[2], pixel[3])
+fn max<T: PartialOrd>(a: T, b: T) -> T {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+#[inline]
+fn min<T: PartialOrd>(a: T, b: T) -> T {
+    if a < b {
+        a
+    } else {
+        b
+    }
 }
 
 // https://stackoverflow.com/a/596241/3894179
 #[inline]
-pub fn get_brightness(r: u8, g: u8, b: u8) -> u32 {
-    (r as u32 + r as u32 + g as u32 + g as u32 + g as u32 + b as u32) / 6
+pub fn get_brightness(r: f32, g: f32, b: f32) -> f32 {
+    (r + r + g + g + g + b) / 6.0
+}
+
+/// Decodes an sRGB encoded value in 0-1 to linear light
+#[inline]
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.040_45 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encodes a linear light value in 0-1 with the sRGB transfer function
+#[inline]
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Converts linear light RGB to OKLab
+pub fn linear_to_oklab(r: f32, g: f32, b: f32) -> (f32, f32, f32) {
+    let l = (0.412_221_47 * r + 0.536_332_55 * g + 0.051_445_995 * b).cbrt();
+    let m = (0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b).cbrt();
+    let s = (0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b).cbrt();
+    (
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    )
+}
+
+/// Converts OKLab back to linear light RGB
+pub fn oklab_to_linear(l: f32, a: f32, b: f32) -> (f32, f32, f32) {
+    let l_ = (l + 0.396_337_78 * a + 0.215_803_76 * b).powi(3);
+    let m = (l - 0.105_561_346 * a - 0.063_854_17 * b).powi(3);
+    let s = (l - 0.089_484_18 * a - 1.291_485_5 * b).powi(3);
+    (
+        4.076_741_7 * l_ - 3.307_711_6 * m + 0.230_969_94 * s,
+        -1.268_438 * l_ + 2.609_757_4 * m - 0.341_319_4 * s,
+        -0.004_196_086_3 * l_ - 0.703_418_6 * m + 1.707_614_7 * s,
+    )
 }
 
-pub fn get_largest_alpha_avg(
-    cc: image::Rgba<u8>,
-    lightest_color: image::Rgba<u8>,
-    a: image::Rgba<u8>,
-    b: image::Rgba<u8>,
-    c: image::Rgba<u8>,
+/// Weights of the source pixels covered by each output pixel when reducing
+/// `src` pixels to `dst` pixels with a box filter
+fn area_weights(src: u32, dst: u32) -> Vec<Vec<(u32, f64)>> {
+    let ratio = src as f64 / dst as f64;
+    (0..dst)
+        .map(|i| {
+            let start = i as f64 * ratio;
+            let end = start + ratio;
+            let mut weights = Vec::new();
+            let mut j = start.floor() as u32;
+            while (j as f64) < end && j < src {
+                let coverage = end.min(j as f64 + 1.0) - start.max(j as f64);
+                if coverage > 0.0 {
+                    weights.push((j, coverage / ratio));
+                }
+                j += 1;
+            }
+            weights
+        })
+        .collect()
+}
+
+/// Weighting of the RGB channels used to compute the luminance
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LumaModel {
+    /// The `(2R + 3G + B) / 6` approximation of `get_brightness`
+    #[default]
+    Approximate,
+    /// ITU-R BT.601, standard definition video
+    Bt601,
+    /// ITU-R BT.709, high definition video
+    Bt709,
+    /// ITU-R BT.2020, ultra high definition video
+    Bt2020,
+}
+
+impl FromStr for LumaModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LumaModel, String> {
+        match s {
+            "approximate" => Ok(LumaModel::Approximate),
+            "bt601" => Ok(LumaModel::Bt601),
+            "bt709" => Ok(LumaModel::Bt709),
+            "bt2020" => Ok(LumaModel::Bt2020),
+            _ => Err(format!("Unknown luma model {}", s)),
+        }
+    }
+}
+
+impl LumaModel {
+    /// Returns the red, green and blue weights, summing to 1
+    pub fn weights(self) -> (f64, f64, f64) {
+        match self {
+            LumaModel::Approximate => (2.0 / 6.0, 3.0 / 6.0, 1.0 / 6.0),
+            LumaModel::Bt601 => (0.299, 0.587, 0.114),
+            LumaModel::Bt709 => (0.2126, 0.7152, 0.0722),
+            LumaModel::Bt2020 => (0.2627, 0.678, 0.0593),
+        }
+    }
+
+    #[inline]
+    pub fn luma(self, r: f32, g: f32, b: f32) -> f32 {
+        match self {
+            LumaModel::Approximate => get_brightness(r, g, b),
+            _ => {
+                let (wr, wg, wb) = self.weights();
+                wr as f32 * r + wg as f32 * g + wb as f32 * b
+            }
+        }
+    }
+}
+
+/// Pixel the passes can run on. While they run, the passes keep the luminance
+/// and then the gradient of every pixel in a scratch value, which is the
+/// channel after the colors when the pixel has one and a separate plane of the
+/// kernel when it doesn't.
+pub trait KernelPixel: image::Pixel<Subpixel = f32> + 'static {
+    /// Number of color channels
+    const COLORS: usize;
+
+    /// Luminance of the color channels
+    fn luminance(&self, model: LumaModel) -> f32;
+
+    /// Converts an RGBA color to this kind of pixel
+    fn from_rgba(color: image::Rgba<f32>, model: LumaModel) -> Self;
+
+    /// Whether the scratch value is kept in the pixel itself
+    #[inline]
+    fn has_scratch_channel() -> bool {
+        Self::CHANNEL_COUNT as usize > Self::COLORS
+    }
+}
+
+impl KernelPixel for image::Rgba<f32> {
+    const COLORS: usize = 3;
+
+    #[inline]
+    fn luminance(&self, model: LumaModel) -> f32 {
+        model.luma(self[0], self[1], self[2])
+    }
+
+    fn from_rgba(color: image::Rgba<f32>, _: LumaModel) -> Self {
+        color
+    }
+}
+
+impl KernelPixel for image::LumaA<f32> {
+    const COLORS: usize = 1;
+
+    #[inline]
+    fn luminance(&self, _: LumaModel) -> f32 {
+        self[0]
+    }
+
+    fn from_rgba(color: image::Rgba<f32>, model: LumaModel) -> Self {
+        image::LumaA([model.luma(color[0], color[1], color[2]), color[3]])
+    }
+}
+
+impl KernelPixel for image::Luma<f32> {
+    const COLORS: usize = 1;
+
+    #[inline]
+    fn luminance(&self, _: LumaModel) -> f32 {
+        self[0]
+    }
+
+    fn from_rgba(color: image::Rgba<f32>, model: LumaModel) -> Self {
+        image::Luma([model.luma(color[0], color[1], color[2])])
+    }
+}
+
+/// Pixel of a pass together with its scratch value
+#[derive(Clone, Copy, Debug)]
+pub struct Sample<P> {
+    pub pixel: P,
+    pub scratch: f32,
+}
+
+impl<P: KernelPixel> Sample<P> {
+    #[inline]
+    fn alpha(&self) -> f32 {
+        self.scratch
+    }
+}
+
+/// Writes `sample` to `image`, its scratch value going to the channel of the
+/// pixel or to `scratch` when the pixel has none
+fn put_sample<P: KernelPixel>(
+    image: &mut image::ImageBuffer<P, Vec<f32>>,
+    scratch: &mut [f32],
+    x: u32,
+    y: u32,
+    sample: Sample<P>,
+) {
+    let mut pixel = sample.pixel;
+    if P::has_scratch_channel() {
+        pixel.channels_mut()[P::COLORS] = sample.scratch;
+    } else {
+        scratch[(y * image.width() + x) as usize] = sample.scratch;
+    }
+    image.put_pixel(x, y, pixel);
+}
+
+/// Converts RGB to YCbCr with the weights of `model`, Cb and Cr being offset by
+/// 0.5 the way 8 bit video offsets them by 128
+pub fn rgb_to_ycbcr(model: LumaModel, r: f32, g: f32, b: f32) -> (f32, f32, f32) {
+    let (wr, _, wb) = model.weights();
+    let y = model.luma(r, g, b);
+    let cb = (b - y) / (2.0 * (1.0 - wb as f32)) + 0.5;
+    let cr = (r - y) / (2.0 * (1.0 - wr as f32)) + 0.5;
+    (y, cb, cr)
+}
+
+/// Inverse of `rgb_to_ycbcr`
+pub fn ycbcr_to_rgb(model: LumaModel, y: f32, cb: f32, cr: f32) -> (f32, f32, f32) {
+    let (wr, wg, wb) = model.weights();
+    let r = y + 2.0 * (1.0 - wr as f32) * (cr - 0.5);
+    let b = y + 2.0 * (1.0 - wb as f32) * (cb - 0.5);
+    let g = (y - wr as f32 * r - wb as f32 * b) / wg as f32;
+    (r, g, b)
+}
+
+pub fn get_largest_alpha_avg<P: KernelPixel>(
+    cc: Sample<P>,
+    lightest_color: Sample<P>,
+    a: Sample<P>,
+    b: Sample<P>,
+    c: Sample<P>,
     strength: u16,
-) -> image::Rgba<u8> {
-    let new_color_r = ((cc[0] as u32 * (0xFF - strength) as u32
-        + ((a[0] as u32 + b[0] as u32 + c[0] as u32) / 3) * strength as u32)
-        / 0xFF) as u8;
-    let new_color_g = ((cc[1] as u32 * (0xFF - strength) as u32
-        + ((a[1] as u32 + b[1] as u32 + c[1] as u32) / 3) * strength as u32)
-        / 0xFF) as u8;
-    let new_color_b = ((cc[2] as u32 * (0xFF - strength) as u32
-        + ((a[2] as u32 + b[2] as u32 + c[2] as u32) / 3) * strength as u32)
-        / 0xFF) as u8;
-    let new_color_a = ((cc[3] as u32 * (0xFF - strength) as u32
-        + ((a[3] as u32 + b[3] as u32 + c[3] as u32) / 3) * strength as u32)
-        / 0xFF) as u8;
-
-    let new_color = image::Rgba::<u8>([new_color_r, new_color_g, new_color_b, new_color_a]);
-
-    if new_color[3] > lightest_color[3] {
+) -> Sample<P> {
+    let new_color = get_alpha_avg(cc, a, b, c, strength);
+
+    if new_color.alpha() > lightest_color.alpha() {
         new_color
     } else {
         lightest_color
     }
 }
 
-pub fn get_alpha_avg(
-    cc: image::Rgba<u8>,
-    a: image::Rgba<u8>,
-    b: image::Rgba<u8>,
-    c: image::Rgba<u8>,
+pub fn get_alpha_avg<P: KernelPixel>(
+    cc: Sample<P>,
+    a: Sample<P>,
+    b: Sample<P>,
+    c: Sample<P>,
     strength: u16,
-) -> image::Rgba<u8> {
-    let new_color_r = ((cc[0] as u32 * (0xFF - strength) as u32
-        + ((a[0] as u32 + b[0] as u32 + c[0] as u32) / 3) * strength as u32)
-        / 0xFF) as u8;
-    let new_color_g = ((cc[1] as u32 * (0xFF - strength) as u32
-        + ((a[1] as u32 + b[1] as u32 + c[1] as u32) / 3) * strength as u32)
-        / 0xFF) as u8;
-    let new_color_b = ((cc[2] as u32 * (0xFF - strength) as u32
-        + ((a[2] as u32 + b[2] as u32 + c[2] as u32) / 3) * strength as u32)
-        / 0xFF) as u8;
-    let new_color_a = ((cc[3] as u32 * (0xFF - strength) as u32
-        + ((a[3] as u32 + b[3] as u32 + c[3] as u32) / 3) * strength as u32)
-        / 0xFF) as u8;
+) -> Sample<P> {
+    let strength = strength as f32 / 0xFF as f32;
+    let mut new_color = cc;
+    for (i, value) in new_color.pixel.channels_mut().iter_mut().enumerate() {
+        let average = (a.pixel.channels()[i] + b.pixel.channels()[i] + c.pixel.channels()[i]) / 3.0;
+        *value = cc.pixel.channels()[i] * (1.0 - strength) + average * strength;
+    }
+    let average = (a.scratch + b.scratch + c.scratch) / 3.0;
+    new_color.scratch = cc.scratch * (1.0 - strength) + average * strength;
 
-    image::Rgba::<u8>([new_color_r, new_color_g, new_color_b, new_color_a])
+    new_color
 }
 
-pub struct ImageKernel {
-    pub image: image::ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+/// Resamples `image` without clipping float values above 1. `imageops::resize`
+/// clamps every channel to 0-1, so the image is divided by its peak first and
+/// multiplied back afterward.
+fn resize_buffer<P: image::Pixel<Subpixel = f32> + 'static>(
+    image: &image::ImageBuffer<P, Vec<f32>>,
+    width: u32,
+    height: u32,
+    filter: image::imageops::FilterType,
+) -> image::ImageBuffer<P, Vec<f32>> {
+    let peak = image.iter().cloned().fold(1.0f32, f32::max);
+    if peak <= 1.0 {
+        return image::imageops::resize(image, width, height, filter);
+    }
+
+    let mut normalized = image.clone();
+    for value in normalized.iter_mut() {
+        *value /= peak;
+    }
+    let mut temp_image = image::imageops::resize(&normalized, width, height, filter);
+    for value in temp_image.iter_mut() {
+        *value *= peak;
+    }
+    temp_image
+}
+
+/// Centers `image` on a `width` x `height` canvas filled with `background`,
+/// cropping whatever falls outside of the canvas
+fn reframe_buffer<P: image::Pixel<Subpixel = f32> + 'static>(
+    image: &image::ImageBuffer<P, Vec<f32>>,
+    width: u32,
+    height: u32,
+    background: P,
+) -> image::ImageBuffer<P, Vec<f32>> {
+    let offset_x = (width as i64 - image.width() as i64) / 2;
+    let offset_y = (height as i64 - image.height() as i64) / 2;
+    let mut temp_image = image::ImageBuffer::from_pixel(width, height, background);
+    for y in 0..height {
+        for x in 0..width {
+            let source_x = x as i64 - offset_x;
+            let source_y = y as i64 - offset_y;
+            if source_x >= 0
+                && source_y >= 0
+                && source_x < image.width() as i64
+                && source_y < image.height() as i64
+            {
+                temp_image.put_pixel(x, y, *image.get_pixel(source_x as u32, source_y as u32));
+            }
+        }
+    }
+    temp_image
+}
+
+/// Response curve mapping the Sobel magnitude to the gradient stored by
+/// `compute_gradient`. The default reproduces the plain hard clamp at 255.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GradientCurve {
+    /// Multiplier applied to the Sobel magnitude before anything else
+    pub gain: f64,
+    /// Magnitudes below this value (0-255, after gain) are treated as flat
+    pub threshold: f64,
+    /// Exponent applied to the normalized response
+    pub gamma: f64,
+    /// Width of the soft knee around full scale (0-1), 0 means hard clamp
+    pub knee: f64,
+}
+
+impl Default for GradientCurve {
+    fn default() -> GradientCurve {
+        GradientCurve {
+            gain: 1.0,
+            threshold: 0.0,
+            gamma: 1.0,
+            knee: 0.0,
+        }
+    }
+}
+
+impl GradientCurve {
+    /// Returns the edge response in 0-255 for a Sobel magnitude
+    pub fn response(&self, magnitude: f64) -> f64 {
+        let magnitude = magnitude * self.gain;
+        if magnitude < self.threshold {
+            return 0.0;
+        }
+
+        let mut value = magnitude / 255.0;
+        if self.knee > 0.0 {
+            // Quadratic soft knee, reaching full scale at 1 + knee
+            let knee = self.knee;
+            if value >= 1.0 + knee {
+                value = 1.0;
+            } else if value > 1.0 - knee {
+                let over = value - (1.0 - knee);
+                value -= over * over / (4.0 * knee);
+            }
+        }
+        value = clamp(value, 0.0, 1.0).powf(self.gamma);
+
+        value * 255.0
+    }
+}
+
+/// Reduction filter used when the image is made smaller
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DownscaleFilter {
+    /// Averages every source pixel by the area it covers
+    Area,
+    /// Lanczos3, with the kernel stretched by the reduction factor
+    Lanczos,
+}
+
+impl FromStr for DownscaleFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DownscaleFilter, String> {
+        match s {
+            "area" => Ok(DownscaleFilter::Area),
+            "lanczos" => Ok(DownscaleFilter::Lanczos),
+            _ => Err(format!("Unknown downscale filter {}", s)),
+        }
+    }
+}
+
+/// Filter the chroma planes are scaled with in YCbCr mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChromaFilter {
+    Nearest,
+    Bilinear,
+    Bicubic,
+    Lanczos,
+}
+
+impl FromStr for ChromaFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ChromaFilter, String> {
+        match s {
+            "nearest" => Ok(ChromaFilter::Nearest),
+            "bilinear" => Ok(ChromaFilter::Bilinear),
+            "bicubic" => Ok(ChromaFilter::Bicubic),
+            "lanczos" => Ok(ChromaFilter::Lanczos),
+            _ => Err(format!("Unknown chroma filter {}", s)),
+        }
+    }
+}
+
+impl ChromaFilter {
+    fn filter_type(self) -> image::imageops::FilterType {
+        match self {
+            ChromaFilter::Nearest => image::imageops::FilterType::Nearest,
+            ChromaFilter::Bilinear => image::imageops::FilterType::Triangle,
+            ChromaFilter::Bicubic => image::imageops::FilterType::CatmullRom,
+            ChromaFilter::Lanczos => image::imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Color space the push_color blends are done in
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColorBlend {
+    /// Blends every channel as it is, like the original Anime4K
+    #[default]
+    Rgb,
+    /// Blends in OKLab, so that saturated outlines don't drift in hue
+    Oklab,
+    /// Moves the luma only, holding the chroma of every pixel
+    Luma,
+}
+
+impl FromStr for ColorBlend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ColorBlend, String> {
+        match s {
+            "rgb" => Ok(ColorBlend::Rgb),
+            "oklab" => Ok(ColorBlend::Oklab),
+            "luma" => Ok(ColorBlend::Luma),
+            _ => Err(format!("Unknown color blend {}", s)),
+        }
+    }
+}
+
+/// Scales the push strength per pixel from the local contrast, measured as the
+/// standard deviation of the brightness over a square window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveStrength {
+    /// Radius of the window, a radius of 2 gives a 5x5 window
+    pub radius: u32,
+    /// Fraction of the pass strength used on perfectly flat areas
+    pub min: f64,
+    /// Fraction of the pass strength used on the highest contrast areas
+    pub max: f64,
+}
+
+impl Default for AdaptiveStrength {
+    fn default() -> AdaptiveStrength {
+        AdaptiveStrength {
+            radius: 2,
+            min: 0.5,
+            max: 1.0,
+        }
+    }
+}
+
+/// A decoded image file along with what it carries besides the pixels
+pub struct Source {
+    pub image: image::DynamicImage,
+    pub icc_profile: Option<Vec<u8>>,
+    /// EXIF, text and pixel density
+    pub metadata: Metadata,
+    /// Palette of an indexed PNG or a GIF
+    pub palette: Option<Palette>,
+    /// Every frame of an animated GIF
+    pub animation: Option<Animation>,
+}
+
+/// Decodes an image file, `-` being stdin. The format is `format` if given,
+/// else the one of the extension, else guessed from the content.
+pub fn read(filename: &str, format: Option<image::ImageFormat>) -> image::ImageResult<Source> {
+    let encoded = if filename == "-" {
+        let mut encoded = Vec::new();
+        std::io::stdin().read_to_end(&mut encoded)?;
+        encoded
+    } else {
+        std::fs::read(filename)?
+    };
+    let format = match format {
+        Some(format) => format,
+        None => {
+            image::ImageFormat::from_path(filename).or_else(|_| image::guess_format(&encoded))?
+        }
+    };
+    let animation = match format {
+        image::ImageFormat::Gif => Some(Animation::read_gif(&encoded)?),
+        image::ImageFormat::Png if image::codecs::png::PngDecoder::new(&encoded[..])?.is_apng() => {
+            Some(Animation::read_apng(&encoded)?)
+        }
+        _ => None,
+    }
+    .filter(|animation| animation.frames.len() > 1);
+    let image = match format {
+        // image only decodes AVIF with dav1d, libheif is used instead
+        image::ImageFormat::Avif => heif::decode(&encoded)?,
+        _ => image::load_from_memory_with_format(&encoded, format)?,
+    };
+    let mut metadata = Metadata::read(&encoded, (image.width(), image.height()));
+    Ok(Source {
+        image: metadata.orient(image),
+        icc_profile: icc::extract(&encoded),
+        metadata,
+        palette: Palette::read(&encoded),
+        animation,
+    })
+}
+
+fn output_format(
+    filename: &str,
+    format: Option<image::ImageFormat>,
+) -> image::ImageResult<image::ImageFormat> {
+    match format {
+        Some(format) => Ok(format),
+        None => image::ImageFormat::from_path(filename),
+    }
+}
+
+/// A source that carries nothing but its pixels, such as a raw buffer
+impl From<image::DynamicImage> for Source {
+    fn from(image: image::DynamicImage) -> Source {
+        Source {
+            metadata: Metadata {
+                size: (image.width(), image.height()),
+                ..Metadata::default()
+            },
+            image,
+            icc_profile: None,
+            palette: None,
+            animation: None,
+        }
+    }
+}
+
+/// Opens `filename` for writing, `-` being stdout
+pub fn create(filename: &str) -> std::io::Result<Box<dyn Write>> {
+    if filename == "-" {
+        Ok(Box::new(std::io::stdout()))
+    } else {
+        Ok(Box::new(std::io::BufWriter::new(std::fs::File::create(
+            filename,
+        )?)))
+    }
+}
+
+/// Wraps the error of an encoder that isn't driven through `image`
+pub fn encoding_error<E>(format: image::ImageFormat, error: E) -> image::ImageError
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    image::ImageError::Encoding(image::error::EncodingError::new(format.into(), error))
+}
+
+/// Encodes `image` as `format`, embedding `icc_profile` and `metadata` when
+/// the format can carry them.
+/// `encoding` tunes the formats that have settings, and transparency is laid
+/// over its background for formats without alpha.
+fn write(
+    image: image::DynamicImage,
+    icc_profile: Option<&[u8]>,
+    metadata: &Metadata,
+    filename: &str,
+    format: image::ImageFormat,
+    encoding: &Encoding,
+) -> image::ImageResult<()> {
+    let size = (image.width(), image.height());
+    let image = if codec::has_alpha(filename, format) {
+        image
+    } else {
+        codec::flatten(image, encoding.background)
+    };
+    let encoded = match format {
+        image::ImageFormat::Png => codec::png(&image, encoding)?,
+        image::ImageFormat::Jpeg => {
+            codec::jpeg(&image, encoding).map_err(|e| encoding_error(format, e))?
+        }
+        image::ImageFormat::WebP => {
+            codec::webp(&image.to_rgba8(), encoding).map_err(|e| encoding_error(format, e))?
+        }
+        image::ImageFormat::Avif => {
+            codec::avif(&image.to_rgba8(), encoding).map_err(|e| encoding_error(format, e))?
+        }
+        // Without an extension to tell the PNM variant, the flattened image
+        // goes out as PPM or PGM
+        image::ImageFormat::Pnm if filename == "-" => codec::pnm(&image)?,
+        _ if icc_profile.is_none() && filename != "-" => {
+            return image.save_with_format(filename, format)
+        }
+        _ => {
+            let mut encoded = std::io::Cursor::new(Vec::new());
+            image.write_to(&mut encoded, format)?;
+            encoded.into_inner()
+        }
+    };
+    let encoded = match icc_profile {
+        Some(profile) => icc::embed(encoded, profile),
+        None => encoded,
+    };
+    let mut output = create(filename)?;
+    output.write_all(&metadata.embed(encoded, size))?;
+    output.flush()?;
+    Ok(())
+}
+
+/// How the working values are taken to the integer levels of the output
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Quantization {
+    /// Drops the fraction, like the original `as u8` casts
+    #[default]
+    Truncate,
+    /// Takes the nearest level
+    Round,
+    /// Dithers with an 8x8 Bayer matrix
+    Bayer,
+    /// Dithers with a tile of blue noise generated from this seed
+    BlueNoise(u64),
+}
+
+impl FromStr for Quantization {
+    type Err = String;
+
+    /// Blue noise is parsed with a seed of 0
+    fn from_str(s: &str) -> Result<Quantization, String> {
+        match s {
+            "truncate" => Ok(Quantization::Truncate),
+            "round" => Ok(Quantization::Round),
+            "bayer" => Ok(Quantization::Bayer),
+            "blue-noise" => Ok(Quantization::BlueNoise(0)),
+            _ => Err(format!("Unknown quantization {}", s)),
+        }
+    }
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Share of a level truncation lets a value fall short of it. Values that went
+/// through the sRGB transfer function and back land a hair below their level,
+/// which isn't a fraction to drop.
+const TRUNCATE_TOLERANCE: f32 = 1.0 / 64.0;
+
+/// Seeded tile of blue noise with values in -0.5..0.5. White noise minus its
+/// blurred self keeps the high frequencies, ranking then evens out the histogram.
+fn blue_noise(seed: u64) -> Vec<f32> {
+    let area = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+    // SplitMix64
+    let mut state = seed;
+    let white: Vec<f32> = (0..area)
+        .map(|_| {
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            ((z ^ (z >> 31)) >> 40) as f32 / (1u64 << 24) as f32
+        })
+        .collect();
+
+    let size = BLUE_NOISE_SIZE;
+    let mut high = vec![0.0f32; area];
+    for y in 0..size {
+        for x in 0..size {
+            let mut blur = 0.0;
+            for dy in 0..3 {
+                for dx in 0..3 {
+                    blur += white[(y + size + dy - 1) % size * size + (x + size + dx - 1) % size];
+                }
+            }
+            high[y * size + x] = white[y * size + x] - blur / 9.0;
+        }
+    }
+
+    let mut order: Vec<usize> = (0..area).collect();
+    order.sort_by(|&a, &b| high[a].partial_cmp(&high[b]).unwrap());
+    let mut tile = vec![0.0f32; area];
+    for (rank, &i) in order.iter().enumerate() {
+        tile[i] = (rank as f32 + 0.5) / area as f32 - 0.5;
+    }
+    tile
+}
+
+/// Precision of the source image, the output is saved with the same one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+    /// Scene-referred float data, values may go above 1
+    Float,
+}
+
+impl BitDepth {
+    pub fn of(color: image::ColorType) -> BitDepth {
+        match color {
+            image::ColorType::L16
+            | image::ColorType::La16
+            | image::ColorType::Rgb16
+            | image::ColorType::Rgba16 => BitDepth::Sixteen,
+            image::ColorType::Rgb32F | image::ColorType::Rgba32F => BitDepth::Float,
+            _ => BitDepth::Eight,
+        }
+    }
+}
+
+/// Working image with every channel in 0-1, or above 1 for HDR sources. The
+/// alpha channel holds the luminance and then the gradient while the passes
+/// run, pixels without one keep them in a separate plane.
+pub struct ImageKernel<P: KernelPixel = image::Rgba<f32>> {
+    pub image: image::ImageBuffer<P, Vec<f32>>,
+    /// Luminance and then gradient of pixels without an alpha channel, only
+    /// filled while the passes run
+    scratch: Vec<f32>,
+    pub luma_model: LumaModel,
+    pub bit_depth: BitDepth,
+    /// ICC profile of the color values, `None` meaning sRGB
+    pub icc_profile: Option<Vec<u8>>,
+    /// EXIF, text and pixel density carried over from the source
+    pub metadata: Metadata,
+    /// Whether the color values are linear light rather than sRGB encoded
+    pub linear: bool,
+    /// How the values are taken to integer levels when saving
+    pub quantization: Quantization,
+    /// Settings of the WebP and AVIF encoders
+    pub encoding: Encoding,
 }
 
 impl ImageKernel {
     pub fn from_image(image: image::DynamicImage) -> ImageKernel {
+        let bit_depth = BitDepth::of(image.color());
+        ImageKernel {
+            bit_depth,
+            image: image.to_rgba32f(),
+            scratch: Vec::new(),
+            luma_model: LumaModel::default(),
+            icc_profile: None,
+            metadata: Metadata::default(),
+            // Float sources are scene-referred linear light already
+            linear: bit_depth == BitDepth::Float,
+            quantization: Quantization::default(),
+            encoding: Encoding::default(),
+        }
+    }
+
+    /// Replaces the pixels with `image` and keeps the settings, so that one
+    /// kernel goes through a whole sequence
+    pub fn load(&mut self, image: image::DynamicImage) {
+        self.bit_depth = BitDepth::of(image.color());
+        self.linear = self.bit_depth == BitDepth::Float;
+        self.image = image.to_rgba32f();
+    }
+
+    /// Converts the color values to the `target` ICC profile. The profiles
+    /// take encoded values, so linear light is sRGB encoded for the
+    /// conversion and decoded again after it.
+    pub fn convert_profile(&mut self, target: &[u8]) -> Result<(), String> {
+        let linear = self.linear;
+        if linear {
+            self.encode_srgb();
+        }
+        let converted = icc::convert(&mut self.image, self.icc_profile.as_deref(), target);
+        if linear {
+            self.decode_srgb();
+        }
+        converted?;
+        self.icc_profile = Some(target.to_vec());
+        Ok(())
+    }
+
+    /// Quantizes the working image back to 8 bits per channel
+    pub fn to_rgba8(&self) -> image::RgbaImage {
+        image::ImageBuffer::from_raw(self.width(), self.height(), self.quantize8())
+            .expect("Load from raw image error")
+    }
+
+    /// Quantizes the working image to 16 bits per channel
+    pub fn to_rgba16(&self) -> image::ImageBuffer<image::Rgba<u16>, Vec<u16>> {
+        image::ImageBuffer::from_raw(self.width(), self.height(), self.quantize16())
+            .expect("Load from raw image error")
+    }
+
+    /// Splits the image into a luma kernel and the chroma planes
+    pub fn to_ycbcr(&self) -> YCbCrKernel {
+        let (width, height) = self.image.dimensions();
+        let mut luma = image::ImageBuffer::new(width, height);
+        let mut cb = image::ImageBuffer::new(width, height);
+        let mut cr = image::ImageBuffer::new(width, height);
+        for (x, y, pixel) in self.image.enumerate_pixels() {
+            let (y_value, cb_value, cr_value) =
+                rgb_to_ycbcr(self.luma_model, pixel[0], pixel[1], pixel[2]);
+            luma.put_pixel(x, y, image::LumaA([y_value, pixel[3]]));
+            cb.put_pixel(x, y, image::Luma([cb_value]));
+            cr.put_pixel(x, y, image::Luma([cr_value]));
+        }
+
+        YCbCrKernel {
+            luma: ImageKernel {
+                image: luma,
+                scratch: Vec::new(),
+                luma_model: self.luma_model,
+                bit_depth: self.bit_depth,
+                icc_profile: self.icc_profile.clone(),
+                metadata: self.metadata.clone(),
+                linear: self.linear,
+                quantization: self.quantization,
+                encoding: self.encoding,
+            },
+            cb,
+            cr,
+        }
+    }
+
+    /// Saves with the precision and the ICC profile of the source, as far as
+    /// the format allows. `-` is stdout, and the format is the one of the
+    /// extension unless `format` is given.
+    pub fn save(
+        &self,
+        filename: &str,
+        format: Option<image::ImageFormat>,
+    ) -> image::ImageResult<()> {
+        let format = output_format(filename, format)?;
+        let image = match (self.bit_depth, format) {
+            // OpenEXR is always float and holds linear light, whatever the
+            // source was
+            (_, image::ImageFormat::OpenExr) => {
+                let mut image = self.image.clone();
+                if !self.linear {
+                    for pixel in image.pixels_mut() {
+                        for value in pixel.0.iter_mut().take(3) {
+                            *value = srgb_to_linear(*value);
+                        }
+                    }
+                }
+                image::DynamicImage::ImageRgba32F(image)
+            }
+            (BitDepth::Sixteen, image::ImageFormat::Png)
+            | (BitDepth::Sixteen, image::ImageFormat::Tiff) => {
+                image::DynamicImage::ImageRgba16(self.to_rgba16())
+            }
+            _ => image::DynamicImage::ImageRgba8(self.to_rgba8()),
+        };
+
+        write(
+            image,
+            self.icc_profile.as_deref(),
+            &self.metadata,
+            filename,
+            format,
+            &self.encoding,
+        )
+    }
+
+    /// Saves as a headerless buffer of 8 bit pixels
+    pub fn save_raw(
+        &self,
+        filename: &str,
+        format: RawFormat,
+        stride: Option<usize>,
+    ) -> image::ImageResult<()> {
+        raw::write(&self.to_rgba8(), filename, format, stride)
+    }
+
+    /// Quantizes to `palette` and saves as an indexed PNG
+    pub fn save_indexed(
+        &self,
+        filename: &str,
+        palette: &Palette,
+        dither: Dither,
+    ) -> image::ImageResult<()> {
+        let indices = palette.quantize(&self.to_rgba8(), dither);
+        let mut encoded = palette
+            .encode_png(self.width(), self.height(), &indices)
+            .map_err(|e| encoding_error(image::ImageFormat::Png, e))?;
+        if let Some(profile) = &self.icc_profile {
+            encoded = icc::embed(encoded, profile);
+        }
+        let mut output = create(filename)?;
+        output.write_all(&self.metadata.embed(encoded, (self.width(), self.height())))?;
+        output.flush()?;
+        Ok(())
+    }
+}
+
+impl ImageKernel<image::Luma<f32>> {
+    /// Loads a grayscale image as a single plane, the passes keeping the
+    /// luminance and the gradient in a separate one. Alpha is dropped.
+    pub fn from_luma_image(image: image::DynamicImage) -> ImageKernel<image::Luma<f32>> {
+        let bit_depth = BitDepth::of(image.color());
         ImageKernel {
-            image: image.to_rgba(),
+            bit_depth,
+            image: image.to_luma32f(),
+            scratch: Vec::new(),
+            luma_model: LumaModel::default(),
+            icc_profile: None,
+            metadata: Metadata::default(),
+            linear: bit_depth == BitDepth::Float,
+            quantization: Quantization::default(),
+            encoding: Encoding::default(),
         }
     }
 
+    /// Quantizes the working image back to 8 bits
+    pub fn to_luma8(&self) -> image::GrayImage {
+        image::ImageBuffer::from_raw(self.width(), self.height(), self.quantize8())
+            .expect("Load from raw image error")
+    }
+
+    /// Quantizes the working image to 16 bits
+    pub fn to_luma16(&self) -> image::ImageBuffer<image::Luma<u16>, Vec<u16>> {
+        image::ImageBuffer::from_raw(self.width(), self.height(), self.quantize16())
+            .expect("Load from raw image error")
+    }
+
+    /// Saves as grayscale
+    pub fn save(
+        &self,
+        filename: &str,
+        format: Option<image::ImageFormat>,
+    ) -> image::ImageResult<()> {
+        let format = output_format(filename, format)?;
+        let image = match (self.bit_depth, format) {
+            // The OpenEXR encoder only takes RGB(A), in linear light
+            (_, image::ImageFormat::OpenExr) => image::DynamicImage::ImageRgba32F(
+                image::ImageBuffer::from_fn(self.width(), self.height(), |x, y| {
+                    let value = self.image.get_pixel(x, y)[0];
+                    let value = if self.linear {
+                        value
+                    } else {
+                        srgb_to_linear(value)
+                    };
+                    image::Rgba([value, value, value, 1.0])
+                }),
+            ),
+            (BitDepth::Sixteen, image::ImageFormat::Png)
+            | (BitDepth::Sixteen, image::ImageFormat::Tiff) => {
+                image::DynamicImage::ImageLuma16(self.to_luma16())
+            }
+            _ => image::DynamicImage::ImageLuma8(self.to_luma8()),
+        };
+
+        write(
+            image,
+            self.icc_profile.as_deref(),
+            &self.metadata,
+            filename,
+            format,
+            &self.encoding,
+        )
+    }
+}
+
+impl<P: KernelPixel> ImageKernel<P> {
     pub fn width(&self) -> u32 {
         self.image.width()
     }
@@ -93,89 +1009,314 @@ impl ImageKernel {
         self.image.height()
     }
 
+    fn quantize8(&self) -> Vec<u8> {
+        self.quantize(255.0).map(|value| value as u8).collect()
+    }
+
+    fn quantize16(&self) -> Vec<u16> {
+        self.quantize(65535.0).map(|value| value as u16).collect()
+    }
+
+    /// Scales every value to 0-`max` and takes it to an integer level, the
+    /// color channels of a pixel sharing the same dither offset. Linear light
+    /// is clipped to 0-1 and sRGB encoded first, as integer formats expect.
+    fn quantize(&self, max: f32) -> impl Iterator<Item = f32> + '_ {
+        let channels = P::CHANNEL_COUNT as usize;
+        let width = self.image.width() as usize;
+        let quantization = self.quantization;
+        let linear = self.linear;
+        let noise = match quantization {
+            Quantization::BlueNoise(seed) => blue_noise(seed),
+            _ => Vec::new(),
+        };
+
+        self.image.iter().enumerate().map(move |(i, &value)| {
+            let alpha = i % channels >= P::COLORS;
+            let value = clamp(value, 0.0, 1.0);
+            let value = if linear && !alpha {
+                linear_to_srgb(value) * max
+            } else {
+                value * max
+            };
+            let pixel = i / channels;
+            let (x, y) = (pixel % width, pixel / width);
+            let offset = if alpha {
+                // Alpha isn't dithered
+                0.0
+            } else {
+                match quantization {
+                    Quantization::Bayer => bayer(x as u32, y as u32),
+                    Quantization::BlueNoise(_) => {
+                        noise[(y % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x % BLUE_NOISE_SIZE]
+                    }
+                    _ => 0.0,
+                }
+            };
+            match quantization {
+                Quantization::Truncate => (value + TRUNCATE_TOLERANCE).floor(),
+                _ => clamp((value + offset).round(), 0.0, max),
+            }
+        })
+    }
+
+    /// Decodes the sRGB color channels to linear light
+    pub fn decode_srgb(&mut self) {
+        for pixel in self.image.pixels_mut() {
+            for value in pixel.channels_mut().iter_mut().take(P::COLORS) {
+                *value = srgb_to_linear(*value);
+            }
+        }
+        self.linear = true;
+    }
+
+    /// Encodes linear light color channels back to sRGB
+    pub fn encode_srgb(&mut self) {
+        for pixel in self.image.pixels_mut() {
+            for value in pixel.channels_mut().iter_mut().take(P::COLORS) {
+                *value = linear_to_srgb(*value);
+            }
+        }
+        self.linear = false;
+    }
+
+    /// Bicubic (Catmull-Rom) resampling
     pub fn scale(&mut self, width: u32, height: u32) {
-        let mut raster_image = raster::Image {
-            width: self.image.width() as i32,
-            height: self.image.height() as i32,
-            bytes: self.image.clone().into_raw(),
+        self.image = resize_buffer(
+            &self.image,
+            width,
+            height,
+            image::imageops::FilterType::CatmullRom,
+        );
+    }
+
+    /// Centers the image on a `width` x `height` canvas filled with
+    /// `background`, cropping whatever falls outside of the canvas
+    pub fn reframe(&mut self, width: u32, height: u32, background: image::Rgba<f32>) {
+        let background = P::from_rgba(background, self.luma_model);
+        self.image = reframe_buffer(&self.image, width, height, background);
+    }
+
+    /// Reduces the image without aliasing. Stretching the Lanczos kernel by the
+    /// reduction factor low-pass filters the source before it is sampled.
+    pub fn downscale(&mut self, width: u32, height: u32, filter: DownscaleFilter) {
+        match filter {
+            DownscaleFilter::Area => {
+                let weights_x = area_weights(self.image.width(), width);
+                let weights_y = area_weights(self.image.height(), height);
+                let mut temp_image = image::ImageBuffer::new(width, height);
+                for (y, row) in weights_y.iter().enumerate() {
+                    for (x, column) in weights_x.iter().enumerate() {
+                        let mut sum = [0.0f32; 4];
+                        for &(source_y, weight_y) in row {
+                            for &(source_x, weight_x) in column {
+                                let pixel = self.image.get_pixel(source_x, source_y);
+                                for (acc, &value) in sum.iter_mut().zip(pixel.channels()) {
+                                    *acc += value * (weight_x * weight_y) as f32;
+                                }
+                            }
+                        }
+                        let channels = &sum[..P::CHANNEL_COUNT as usize];
+                        temp_image.put_pixel(x as u32, y as u32, *P::from_slice(channels));
+                    }
+                }
+                self.image = temp_image;
+            }
+            DownscaleFilter::Lanczos => {
+                self.image = resize_buffer(
+                    &self.image,
+                    width,
+                    height,
+                    image::imageops::FilterType::Lanczos3,
+                );
+            }
+        }
+    }
+
+    /// Computes the per pixel strength of a push pass, stored row by row
+    pub fn strength_map(&self, strength: u16, adaptive: &AdaptiveStrength) -> Vec<u16> {
+        let width = self.image.width() as usize;
+        let height = self.image.height() as usize;
+
+        // Summed-area tables of the brightness and its square
+        let mut sum = vec![0.0f64; (width + 1) * (height + 1)];
+        let mut sum_sq = vec![0.0f64; (width + 1) * (height + 1)];
+        for y in 0..height {
+            for x in 0..width {
+                let brightness = self
+                    .image
+                    .get_pixel(x as u32, y as u32)
+                    .luminance(self.luma_model) as f64;
+                let i = (y + 1) * (width + 1) + x + 1;
+                sum[i] = brightness + sum[i - 1] + sum[i - width - 1] - sum[i - width - 2];
+                sum_sq[i] = brightness * brightness + sum_sq[i - 1] + sum_sq[i - width - 1]
+                    - sum_sq[i - width - 2];
+            }
+        }
+
+        let radius = adaptive.radius as usize;
+        let mut map = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let x0 = x.saturating_sub(radius);
+                let y0 = y.saturating_sub(radius);
+                let x1 = min(x + radius + 1, width);
+                let y1 = min(y + radius + 1, height);
+                let area = ((x1 - x0) * (y1 - y0)) as f64;
+                let window = |table: &[f64]| {
+                    table[y1 * (width + 1) + x1]
+                        - table[y0 * (width + 1) + x1]
+                        - table[y1 * (width + 1) + x0]
+                        + table[y0 * (width + 1) + x0]
+                };
+
+                let mean = window(&sum) / area;
+                let variance = (window(&sum_sq) / area - mean * mean).max(0.0);
+                // The largest possible deviation of 0-1 values is 0.5, brighter
+                // HDR areas are measured relative to their own level
+                let contrast = clamp(variance.sqrt() / (0.5 * mean.max(1.0)), 0.0, 1.0);
+                let factor = adaptive.min + (adaptive.max - adaptive.min) * contrast;
+
+                map.push(clamp(strength as f64 * factor, 0.0, 0xFFFF as f64) as u16);
+            }
+        }
+        map
+    }
+
+    /// Pixel at `x`, `y` together with its scratch value
+    #[inline]
+    fn sample(&self, x: u32, y: u32) -> Sample<P> {
+        let pixel = *self.image.get_pixel(x, y);
+        let scratch = if P::has_scratch_channel() {
+            pixel.channels()[P::COLORS]
+        } else {
+            self.scratch[(y * self.image.width() + x) as usize]
         };
-        let mode = raster::interpolate::InterpolationMode::Bicubic;
-        raster::interpolate::resample(&mut raster_image, width as i32, height as i32, mode)
-            .expect("Scale error");
-        self.image = image::ImageBuffer::from_raw(width, height, raster_image.bytes)
-            .expect("Load from raw raster image error");
+        Sample { pixel, scratch }
+    }
+
+    /// Zeroed scratch plane for the result of a pass, which pixels keeping
+    /// the scratch value themselves don't need
+    fn new_scratch(&self) -> Vec<f32> {
+        if P::has_scratch_channel() {
+            Vec::new()
+        } else {
+            vec![0.0; (self.image.width() * self.image.height()) as usize]
+        }
     }
 
     pub fn compute_luminance(&mut self) {
-        for y in 0..self.image.height() {
-            for x in 0..self.image.width() {
-                let pixel = self.image.get_pixel_mut(x, y);
-                let (r, g, b, _) = extract_pixel_rgba(*pixel);
-                let brightness = get_brightness(r, g, b);
-                let luminance_value = clamp(brightness, 0, 0xFF);
-
-                pixel[0] = r;
-                pixel[1] = g;
-                pixel[2] = b;
-                pixel[3] = luminance_value as u8;
+        let luma_model = self.luma_model;
+        if P::has_scratch_channel() {
+            for pixel in self.image.pixels_mut() {
+                let brightness = pixel.luminance(luma_model);
+                pixel.channels_mut()[P::COLORS] = brightness;
             }
+        } else {
+            self.scratch = self
+                .image
+                .pixels()
+                .map(|pixel| pixel.luminance(luma_model))
+                .collect();
         }
     }
 
-    pub fn compute_gradient(&mut self) {
-        let sobelx = [[-1, 0, 1], [-2, 0, 2], [-1, 0, 1]];
-        let sobely = [[-1, -2, -1], [0, 0, 0], [1, 2, 1]];
+    pub fn compute_gradient(&mut self, curve: &GradientCurve) {
+        let sobelx = [[-1.0, 0.0, 1.0], [-2.0, 0.0, 2.0], [-1.0, 0.0, 1.0]];
+        let sobely = [[-1.0, -2.0, -1.0], [0.0, 0.0, 0.0], [1.0, 2.0, 1.0]];
 
-        let mut temp_image =
-            image::DynamicImage::new_rgba8(self.image.width(), self.image.height()).to_rgba();
+        let mut temp_image = image::ImageBuffer::new(self.image.width(), self.image.height());
+        let mut temp_scratch = self.new_scratch();
         for y in 1..self.image.height() - 1 {
             for x in 1..self.image.width() - 1 {
-                let dx = self.image.get_pixel(x - 1, y - 1)[3] as i32 * sobelx[0][0]
-                    + self.image.get_pixel(x, y - 1)[3] as i32 * sobelx[0][1]
-                    + self.image.get_pixel(x + 1, y - 1)[3] as i32 * sobelx[0][2]
-                    + self.image.get_pixel(x - 1, y)[3] as i32 * sobelx[1][0]
-                    + self.image.get_pixel(x, y)[3] as i32 * sobelx[1][1]
-                    + self.image.get_pixel(x + 1, y)[3] as i32 * sobelx[1][2]
-                    + self.image.get_pixel(x - 1, y + 1)[3] as i32 * sobelx[2][0]
-                    + self.image.get_pixel(x, y + 1)[3] as i32 * sobelx[2][1]
-                    + self.image.get_pixel(x + 1, y + 1)[3] as i32 * sobelx[2][2];
-
-                let dy = self.image.get_pixel(x - 1, y - 1)[3] as i32 * sobely[0][0]
-                    + self.image.get_pixel(x, y - 1)[3] as i32 * sobely[0][1]
-                    + self.image.get_pixel(x + 1, y - 1)[3] as i32 * sobely[0][2]
-                    + self.image.get_pixel(x - 1, y)[3] as i32 * sobely[1][0]
-                    + self.image.get_pixel(x, y)[3] as i32 * sobely[1][1]
-                    + self.image.get_pixel(x + 1, y)[3] as i32 * sobely[1][2]
-                    + self.image.get_pixel(x - 1, y + 1)[3] as i32 * sobely[2][0]
-                    + self.image.get_pixel(x, y + 1)[3] as i32 * sobely[2][1]
-                    + self.image.get_pixel(x + 1, y + 1)[3] as i32 * sobely[2][2];
-
-                let derivata = (((dx * dx) + (dy * dy)) as f64).sqrt() as u32;
-
-                let pixel = self.image.get_pixel(x, y);
-                if derivata > 255 {
-                    temp_image.put_pixel(
-                        x,
-                        y,
-                        image::Rgba::<u8>([pixel[0], pixel[1], pixel[2], 0]),
-                    );
-                } else {
-                    temp_image.put_pixel(
-                        x,
-                        y,
-                        image::Rgba::<u8>([pixel[0], pixel[1], pixel[2], (0xFF - derivata) as u8]),
-                    );
+                let dx = self.sample(x - 1, y - 1).alpha() * sobelx[0][0]
+                    + self.sample(x, y - 1).alpha() * sobelx[0][1]
+                    + self.sample(x + 1, y - 1).alpha() * sobelx[0][2]
+                    + self.sample(x - 1, y).alpha() * sobelx[1][0]
+                    + self.sample(x, y).alpha() * sobelx[1][1]
+                    + self.sample(x + 1, y).alpha() * sobelx[1][2]
+                    + self.sample(x - 1, y + 1).alpha() * sobelx[2][0]
+                    + self.sample(x, y + 1).alpha() * sobelx[2][1]
+                    + self.sample(x + 1, y + 1).alpha() * sobelx[2][2];
+
+                let dy = self.sample(x - 1, y - 1).alpha() * sobely[0][0]
+                    + self.sample(x, y - 1).alpha() * sobely[0][1]
+                    + self.sample(x + 1, y - 1).alpha() * sobely[0][2]
+                    + self.sample(x - 1, y).alpha() * sobely[1][0]
+                    + self.sample(x, y).alpha() * sobely[1][1]
+                    + self.sample(x + 1, y).alpha() * sobely[1][2]
+                    + self.sample(x - 1, y + 1).alpha() * sobely[2][0]
+                    + self.sample(x, y + 1).alpha() * sobely[2][1]
+                    + self.sample(x + 1, y + 1).alpha() * sobely[2][2];
+
+                // Above the 0-1 range edges are measured relative to the local
+                // luminance, or every HDR highlight would be the strongest edge
+                let mut level = 0.0;
+                for j in y - 1..=y + 1 {
+                    for i in x - 1..=x + 1 {
+                        level += self.sample(i, j).alpha();
+                    }
                 }
+                let level = max(level / 9.0, 1.0);
+
+                // The curve works on the 0-255 scale
+                let derivata = ((dx * dx) + (dy * dy)).sqrt() / level * 255.0;
+                let response = curve.response(derivata as f64) as f32 / 255.0;
+
+                let mut pixel = self.sample(x, y);
+                pixel.scratch = 1.0 - response;
+                put_sample(&mut temp_image, &mut temp_scratch, x, y, pixel);
             }
         }
         self.image = temp_image;
+        self.scratch = temp_scratch;
     }
 
-    pub fn push_color(&mut self, strength: u16) {
-        let mut temp_image =
-            image::DynamicImage::new_rgba8(self.image.width(), self.image.height()).to_rgba();
+    /// Applies `f` to the color channels of every pixel, single channel
+    /// kernels have no color to map
+    fn map_color<F: Fn(f32, f32, f32) -> (f32, f32, f32)>(&mut self, f: F) {
+        if P::COLORS < 3 {
+            return;
+        }
+        for pixel in self.image.pixels_mut() {
+            let channels = pixel.channels_mut();
+            let (r, g, b) = f(channels[0], channels[1], channels[2]);
+            channels[0] = r;
+            channels[1] = g;
+            channels[2] = b;
+        }
+    }
+
+    pub fn push_color(
+        &mut self,
+        strength: u16,
+        adaptive: Option<&AdaptiveStrength>,
+        blend: ColorBlend,
+    ) {
+        let linear = self.linear;
+        let source = match blend {
+            ColorBlend::Luma => Some(self.image.clone()),
+            _ => None,
+        };
+        if blend == ColorBlend::Oklab {
+            self.map_color(|r, g, b| {
+                if linear {
+                    linear_to_oklab(r, g, b)
+                } else {
+                    linear_to_oklab(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b))
+                }
+            });
+        }
+
+        let strengths = adaptive.map(|adaptive| self.strength_map(strength, adaptive));
+        let mut temp_image = image::ImageBuffer::new(self.image.width(), self.image.height());
+        let mut temp_scratch = self.new_scratch();
         for y in 0..self.image.height() {
             for x in 0..self.image.width() {
+                let strength = match &strengths {
+                    Some(map) => map[(y * self.image.width() + x) as usize],
+                    None => strength,
+                };
+
                 /*
                  * Kernel defination:
                  * --------------
@@ -202,56 +1343,48 @@ impl ImageKernel {
                 }
 
                 // Top column
-                let tl = *self
-                    .image
-                    .get_pixel((x as i32 + x_l) as u32, (y as i32 + y_t) as u32);
-                let tc = *self.image.get_pixel(x, (y as i32 + y_t) as u32);
-                let tr = *self
-                    .image
-                    .get_pixel((x as i32 + x_r) as u32, (y as i32 + y_t) as u32);
+                let tl = self.sample((x as i32 + x_l) as u32, (y as i32 + y_t) as u32);
+                let tc = self.sample(x, (y as i32 + y_t) as u32);
+                let tr = self.sample((x as i32 + x_r) as u32, (y as i32 + y_t) as u32);
 
                 // Middle column
-                let ml = *self.image.get_pixel((x as i32 + x_l) as u32, y);
-                let mc = *self.image.get_pixel(x, y);
-                let mr = *self.image.get_pixel((x as i32 + x_r) as u32, y);
+                let ml = self.sample((x as i32 + x_l) as u32, y);
+                let mc = self.sample(x, y);
+                let mr = self.sample((x as i32 + x_r) as u32, y);
 
                 // Bottom column
-                let bl = *self
-                    .image
-                    .get_pixel((x as i32 + x_l) as u32, (y as i32 + y_b) as u32);
-                let bc = *self.image.get_pixel(x, (y as i32 + y_b) as u32);
-                let br = *self
-                    .image
-                    .get_pixel((x as i32 + x_r) as u32, (y as i32 + y_b) as u32);
+                let bl = self.sample((x as i32 + x_l) as u32, (y as i32 + y_b) as u32);
+                let bc = self.sample(x, (y as i32 + y_b) as u32);
+                let br = self.sample((x as i32 + x_r) as u32, (y as i32 + y_b) as u32);
 
                 let mut lightest_color = mc;
 
                 // Kernel 0 and 4
-                let mut max_dark = max(bl[3], max(bc[3], br[3]));
-                let mut min_light = min(tl[3], min(tc[3], tr[3]));
+                let mut max_dark = max(bl.alpha(), max(bc.alpha(), br.alpha()));
+                let mut min_light = min(tl.alpha(), min(tc.alpha(), tr.alpha()));
 
-                if min_light > mc[3] && min_light > max_dark {
+                if min_light > mc.alpha() && min_light > max_dark {
                     lightest_color =
                         get_largest_alpha_avg(mc, lightest_color, tl, tc, tr, strength);
                 } else {
-                    max_dark = max(tl[3], max(tc[3], tr[3]));
-                    min_light = min(br[3], min(bc[3], bl[3]));
-                    if min_light > mc[3] && min_light > max_dark {
+                    max_dark = max(tl.alpha(), max(tc.alpha(), tr.alpha()));
+                    min_light = min(br.alpha(), min(bc.alpha(), bl.alpha()));
+                    if min_light > mc.alpha() && min_light > max_dark {
                         lightest_color =
                             get_largest_alpha_avg(mc, lightest_color, br, bc, bl, strength);
                     }
                 }
 
                 // Kernel 1 and 5
-                max_dark = max(mc[3], max(ml[3], bc[3]));
-                min_light = min(mr[3], min(tc[3], tr[3]));
+                max_dark = max(mc.alpha(), max(ml.alpha(), bc.alpha()));
+                min_light = min(mr.alpha(), min(tc.alpha(), tr.alpha()));
 
                 if min_light > max_dark {
                     lightest_color =
                         get_largest_alpha_avg(mc, lightest_color, mr, tc, tr, strength);
                 } else {
-                    max_dark = max(mc[3], max(mr[3], tc[3]));
-                    min_light = min(bl[3], min(ml[3], bc[3]));
+                    max_dark = max(mc.alpha(), max(mr.alpha(), tc.alpha()));
+                    min_light = min(bl.alpha(), min(ml.alpha(), bc.alpha()));
                     if min_light > max_dark {
                         lightest_color =
                             get_largest_alpha_avg(mc, lightest_color, bl, ml, bc, strength);
@@ -259,48 +1392,78 @@ impl ImageKernel {
                 }
 
                 // Kernel 2 and 6
-                max_dark = max(ml[3], max(tl[3], bl[3]));
-                min_light = min(mr[3], min(tr[3], br[3]));
+                max_dark = max(ml.alpha(), max(tl.alpha(), bl.alpha()));
+                min_light = min(mr.alpha(), min(tr.alpha(), br.alpha()));
 
-                if min_light > mc[3] && min_light > max_dark {
+                if min_light > mc.alpha() && min_light > max_dark {
                     lightest_color =
                         get_largest_alpha_avg(mc, lightest_color, mr, br, tr, strength);
                 } else {
-                    max_dark = max(mr[3], max(tr[3], br[3]));
-                    min_light = min(ml[3], min(tl[3], bl[3]));
-                    if min_light > mc[3] && min_light > max_dark {
+                    max_dark = max(mr.alpha(), max(tr.alpha(), br.alpha()));
+                    min_light = min(ml.alpha(), min(tl.alpha(), bl.alpha()));
+                    if min_light > mc.alpha() && min_light > max_dark {
                         lightest_color =
                             get_largest_alpha_avg(mc, lightest_color, ml, tl, bl, strength);
                     }
                 }
 
                 // Kernel 3 and 7
-                max_dark = max(mc[3], max(ml[3], tc[3]));
-                min_light = min(mr[3], min(br[3], bc[3]));
+                max_dark = max(mc.alpha(), max(ml.alpha(), tc.alpha()));
+                min_light = min(mr.alpha(), min(br.alpha(), bc.alpha()));
 
                 if min_light > max_dark {
                     lightest_color =
                         get_largest_alpha_avg(mc, lightest_color, mr, br, bc, strength);
                 } else {
-                    max_dark = max(mc[3], max(mr[3], bc[3]));
-                    min_light = min(tc[3], min(ml[3], tl[3]));
+                    max_dark = max(mc.alpha(), max(mr.alpha(), bc.alpha()));
+                    min_light = min(tc.alpha(), min(ml.alpha(), tl.alpha()));
                     if min_light > max_dark {
                         lightest_color =
                             get_largest_alpha_avg(mc, lightest_color, tc, ml, tl, strength);
                     }
                 }
 
-                temp_image.put_pixel(x, y, lightest_color);
+                put_sample(&mut temp_image, &mut temp_scratch, x, y, lightest_color);
             }
         }
         self.image = temp_image;
+        self.scratch = temp_scratch;
+
+        if blend == ColorBlend::Oklab {
+            self.map_color(|l, a, b| {
+                let (r, g, b) = oklab_to_linear(l, a, b);
+                if linear {
+                    (r, g, b)
+                } else {
+                    (linear_to_srgb(r), linear_to_srgb(g), linear_to_srgb(b))
+                }
+            });
+        }
+        if let (Some(source), true) = (source, P::COLORS == 3) {
+            // Adding the same amount to every channel changes the luma alone
+            let luma_model = self.luma_model;
+            for (pixel, original) in self.image.pixels_mut().zip(source.pixels()) {
+                let original = original.channels();
+                let channels = pixel.channels_mut();
+                let delta = luma_model.luma(channels[0], channels[1], channels[2])
+                    - luma_model.luma(original[0], original[1], original[2]);
+                for i in 0..3 {
+                    channels[i] = original[i] + delta;
+                }
+            }
+        }
     }
 
-    pub fn push_gradient(&mut self, strength: u16) {
-        let mut temp_image =
-            image::DynamicImage::new_rgba8(self.image.width(), self.image.height()).to_rgba();
+    pub fn push_gradient(&mut self, strength: u16, adaptive: Option<&AdaptiveStrength>) {
+        let strengths = adaptive.map(|adaptive| self.strength_map(strength, adaptive));
+        let mut temp_image = image::ImageBuffer::new(self.image.width(), self.image.height());
         for y in 0..self.image.height() {
             for x in 0..self.image.width() {
+                let strength = match &strengths {
+                    Some(map) => map[(y * self.image.width() + x) as usize],
+                    None => strength,
+                };
+
                 /*
                  * Kernel defination:
                  * --------------
@@ -327,94 +1490,199 @@ impl ImageKernel {
                 }
 
                 // Top column
-                let tl = *self
-                    .image
-                    .get_pixel((x as i32 + x_l) as u32, (y as i32 + y_t) as u32);
-                let tc = *self.image.get_pixel(x, (y as i32 + y_t) as u32);
-                let tr = *self
-                    .image
-                    .get_pixel((x as i32 + x_r) as u32, (y as i32 + y_t) as u32);
+                let tl = self.sample((x as i32 + x_l) as u32, (y as i32 + y_t) as u32);
+                let tc = self.sample(x, (y as i32 + y_t) as u32);
+                let tr = self.sample((x as i32 + x_r) as u32, (y as i32 + y_t) as u32);
 
                 // Middle column
-                let ml = *self.image.get_pixel((x as i32 + x_l) as u32, y);
-                let mc = *self.image.get_pixel(x, y);
-                let mr = *self.image.get_pixel((x as i32 + x_r) as u32, y);
+                let ml = self.sample((x as i32 + x_l) as u32, y);
+                let mc = self.sample(x, y);
+                let mr = self.sample((x as i32 + x_r) as u32, y);
 
                 // Bottom column
-                let bl = *self
-                    .image
-                    .get_pixel((x as i32 + x_l) as u32, (y as i32 + y_b) as u32);
-                let bc = *self.image.get_pixel(x, (y as i32 + y_b) as u32);
-                let br = *self
-                    .image
-                    .get_pixel((x as i32 + x_r) as u32, (y as i32 + y_b) as u32);
+                let bl = self.sample((x as i32 + x_l) as u32, (y as i32 + y_b) as u32);
+                let bc = self.sample(x, (y as i32 + y_b) as u32);
+                let br = self.sample((x as i32 + x_r) as u32, (y as i32 + y_b) as u32);
 
                 let mut lightest_color = mc;
 
                 // Kernel 0 and 4
-                let mut max_dark = max(bl[3], max(bc[3], br[3]));
-                let mut min_light = min(tl[3], min(tc[3], tr[3]));
+                let mut max_dark = max(bl.alpha(), max(bc.alpha(), br.alpha()));
+                let mut min_light = min(tl.alpha(), min(tc.alpha(), tr.alpha()));
 
-                if min_light > mc[3] && min_light > max_dark {
+                if min_light > mc.alpha() && min_light > max_dark {
                     lightest_color = get_alpha_avg(mc, tl, tc, tr, strength);
                 } else {
-                    max_dark = max(tl[3], max(tc[3], tr[3]));
-                    min_light = min(br[3], min(bc[3], bl[3]));
-                    if min_light > mc[3] && min_light > max_dark {
+                    max_dark = max(tl.alpha(), max(tc.alpha(), tr.alpha()));
+                    min_light = min(br.alpha(), min(bc.alpha(), bl.alpha()));
+                    if min_light > mc.alpha() && min_light > max_dark {
                         lightest_color = get_alpha_avg(mc, br, bc, bl, strength);
                     }
                 }
 
                 // Kernel 1 and 5
-                max_dark = max(mc[3], max(ml[3], bc[3]));
-                min_light = min(mr[3], min(tc[3], tr[3]));
+                max_dark = max(mc.alpha(), max(ml.alpha(), bc.alpha()));
+                min_light = min(mr.alpha(), min(tc.alpha(), tr.alpha()));
 
                 if min_light > max_dark {
                     lightest_color = get_alpha_avg(mc, mr, tc, tr, strength);
                 } else {
-                    max_dark = max(mc[3], max(mr[3], tc[3]));
-                    min_light = min(bl[3], min(ml[3], bc[3]));
+                    max_dark = max(mc.alpha(), max(mr.alpha(), tc.alpha()));
+                    min_light = min(bl.alpha(), min(ml.alpha(), bc.alpha()));
                     if min_light > max_dark {
                         lightest_color = get_alpha_avg(mc, bl, ml, bc, strength);
                     }
                 }
 
                 // Kernel 2 and 6
-                max_dark = max(ml[3], max(tl[3], bl[3]));
-                min_light = min(mr[3], min(tr[3], br[3]));
+                max_dark = max(ml.alpha(), max(tl.alpha(), bl.alpha()));
+                min_light = min(mr.alpha(), min(tr.alpha(), br.alpha()));
 
-                if min_light > mc[3] && min_light > max_dark {
+                if min_light > mc.alpha() && min_light > max_dark {
                     lightest_color = get_alpha_avg(mc, mr, br, tr, strength);
                 } else {
-                    max_dark = max(mr[3], max(tr[3], br[3]));
-                    min_light = min(ml[3], min(tl[3], bl[3]));
-                    if min_light > mc[3] && min_light > max_dark {
+                    max_dark = max(mr.alpha(), max(tr.alpha(), br.alpha()));
+                    min_light = min(ml.alpha(), min(tl.alpha(), bl.alpha()));
+                    if min_light > mc.alpha() && min_light > max_dark {
                         lightest_color = get_alpha_avg(mc, ml, tl, bl, strength);
                     }
                 }
 
                 // Kernel 3 and 7
-                max_dark = max(mc[3], max(ml[3], tc[3]));
-                min_light = min(mr[3], min(br[3], bc[3]));
+                max_dark = max(mc.alpha(), max(ml.alpha(), tc.alpha()));
+                min_light = min(mr.alpha(), min(br.alpha(), bc.alpha()));
 
                 if min_light > max_dark {
                     lightest_color = get_alpha_avg(mc, mr, br, bc, strength);
                 } else {
-                    max_dark = max(mc[3], max(mr[3], bc[3]));
-                    min_light = min(tc[3], min(ml[3], tl[3]));
+                    max_dark = max(mc.alpha(), max(mr.alpha(), bc.alpha()));
+                    min_light = min(tc.alpha(), min(ml.alpha(), tl.alpha()));
                     if min_light > max_dark {
                         lightest_color = get_alpha_avg(mc, tc, ml, tl, strength);
                     }
                 }
 
-                lightest_color[3] = 255;
-                temp_image.put_pixel(x, y, lightest_color);
+                let mut pixel = lightest_color.pixel;
+                if P::has_scratch_channel() {
+                    pixel.channels_mut()[P::COLORS] = 1.0;
+                }
+                temp_image.put_pixel(x, y, pixel);
             }
         }
         self.image = temp_image;
+        // The gradient is used up, the next iteration starts from the
+        // luminance again
+        self.scratch = Vec::new();
+    }
+}
+
+/// Image split into a luma kernel and the chroma planes, so that the passes
+/// only ever touch luma
+pub struct YCbCrKernel {
+    pub luma: ImageKernel<image::LumaA<f32>>,
+    pub cb: image::ImageBuffer<image::Luma<f32>, Vec<f32>>,
+    pub cr: image::ImageBuffer<image::Luma<f32>, Vec<f32>>,
+}
+
+impl YCbCrKernel {
+    /// Takes the opaque planes of a video frame, in which the chroma may be
+    /// smaller than the luma
+    pub fn from_planes(
+        luma: image::ImageBuffer<image::Luma<f32>, Vec<f32>>,
+        cb: image::ImageBuffer<image::Luma<f32>, Vec<f32>>,
+        cr: image::ImageBuffer<image::Luma<f32>, Vec<f32>>,
+        luma_model: LumaModel,
+        bit_depth: BitDepth,
+    ) -> YCbCrKernel {
+        let (width, height) = luma.dimensions();
+        let luma = image::ImageBuffer::from_fn(width, height, |x, y| {
+            image::LumaA([luma.get_pixel(x, y)[0], 1.0])
+        });
+        YCbCrKernel {
+            luma: ImageKernel {
+                image: luma,
+                scratch: Vec::new(),
+                luma_model,
+                bit_depth,
+                icc_profile: None,
+                metadata: Metadata::default(),
+                linear: false,
+                quantization: Quantization::default(),
+                encoding: Encoding::default(),
+            },
+            cb,
+            cr,
+        }
+    }
+
+    /// Scales the chroma planes to the size of the luma kernel
+    pub fn scale_chroma(&mut self, filter: ChromaFilter) {
+        let (width, height) = self.luma.image.dimensions();
+        self.resize_chroma(width, height, filter);
+    }
+
+    /// Scales the chroma planes to `width` x `height`, which subsamples them
+    /// when it is smaller than the luma
+    pub fn resize_chroma(&mut self, width: u32, height: u32, filter: ChromaFilter) {
+        if self.cb.dimensions() != (width, height) {
+            self.cb = resize_buffer(&self.cb, width, height, filter.filter_type());
+            self.cr = resize_buffer(&self.cr, width, height, filter.filter_type());
+        }
+    }
+
+    /// Centers the planes on a `width` x `height` canvas filled with
+    /// `background`, cropping whatever falls outside of the canvas
+    pub fn reframe(&mut self, width: u32, height: u32, background: image::Rgba<f32>) {
+        let (_, cb, cr) = rgb_to_ycbcr(
+            self.luma.luma_model,
+            background[0],
+            background[1],
+            background[2],
+        );
+        self.luma.reframe(width, height, background);
+        self.cb = reframe_buffer(&self.cb, width, height, image::Luma([cb]));
+        self.cr = reframe_buffer(&self.cr, width, height, image::Luma([cr]));
+    }
+
+    /// Merges the planes back into an RGBA kernel
+    pub fn to_rgba(&self) -> ImageKernel {
+        let (width, height) = self.luma.image.dimensions();
+        let mut image = image::ImageBuffer::new(width, height);
+        for (x, y, pixel) in self.luma.image.enumerate_pixels() {
+            let (r, g, b) = ycbcr_to_rgb(
+                self.luma.luma_model,
+                pixel[0],
+                self.cb.get_pixel(x, y)[0],
+                self.cr.get_pixel(x, y)[0],
+            );
+            image.put_pixel(x, y, image::Rgba([r, g, b, pixel[1]]));
+        }
+
+        ImageKernel {
+            image,
+            scratch: Vec::new(),
+            luma_model: self.luma.luma_model,
+            bit_depth: self.luma.bit_depth,
+            icc_profile: self.luma.icc_profile.clone(),
+            metadata: self.luma.metadata.clone(),
+            linear: self.luma.linear,
+            quantization: self.luma.quantization,
+            encoding: self.luma.encoding,
+        }
     }
 
-    pub fn save(&self, filename: &str) -> std::result::Result<(), std::io::Error> {
-        self.image.save(filename)
+    /// Writes the planes one after the other as headerless 8 bit 4:4:4 YUV
+    pub fn save_yuv(&self, filename: &str) -> std::result::Result<(), std::io::Error> {
+        let mut writer = create(filename)?;
+        let luma = self.luma.image.pixels().map(|pixel| pixel[0]);
+        let cb = self.cb.pixels().map(|pixel| pixel[0]);
+        let cr = self.cr.pixels().map(|pixel| pixel[0]);
+        let data: Vec<u8> = luma
+            .chain(cb)
+            .chain(cr)
+            .map(|value| (clamp(value, 0.0, 1.0) * 255.0).round() as u8)
+            .collect();
+        writer.write_all(&data)?;
+        writer.flush()
     }
 }
@@ -1,105 +1,750 @@
 extern crate clap;
 extern crate image;
-extern crate raster;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use image_kernel::clamp;
 
+mod animation;
+mod codec;
+mod heif;
+mod icc;
 mod image_kernel;
+mod metadata;
+mod palette;
+mod pipeline;
+mod raw;
+mod sequence;
 #[cfg(test)]
 mod test;
+mod video;
+mod y4m;
+
+/// Extensions `--input-format` takes
+const INPUT_FORMATS: [&str; 21] = [
+    "avif", "bmp", "dds", "exr", "ff", "gif", "hdr", "ico", "jpeg", "jpg", "pam", "pbm", "pgm",
+    "png", "ppm", "qoi", "tga", "tif", "tiff", "webp", "y4m",
+];
+
+/// Extensions `--output-format` takes, the ones there is an encoder for. Y4M
+/// is only written for Y4M input, which needs no format set.
+const OUTPUT_FORMATS: [&str; 17] = [
+    "avif", "bmp", "exr", "gif", "ico", "jpeg", "jpg", "pam", "pbm", "pgm", "png", "ppm", "qoi",
+    "tga", "tif", "tiff", "webp",
+];
+
+/// Reports a problem with the input or the options and exits
+fn exit_with_error(message: String) -> ! {
+    eprintln!("Error: {}", message);
+    std::process::exit(1);
+}
+
+/// Parses the value of the `name` argument, exiting with an error that names
+/// it when the value is invalid
+fn parse_arg<T, E: std::fmt::Display>(
+    matches: &ArgMatches,
+    name: &str,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> Option<T> {
+    matches.value_of(name).map(|value| {
+        parse(value)
+            .unwrap_or_else(|e| exit_with_error(format!("Invalid --{} {}: {}", name, value, e)))
+    })
+}
+
+/// Options that set up the pipeline, the only ones the video subcommand takes
+fn pipeline_options<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("scale")
+            .short("s")
+            .long("scale")
+            .default_value("2")
+            .help("Sets the scaling factor"),
+        Arg::with_name("scale-x")
+            .long("scale-x")
+            .takes_value(true)
+            .help("Sets the horizontal scaling factor, overriding scale"),
+        Arg::with_name("scale-y")
+            .long("scale-y")
+            .takes_value(true)
+            .help("Sets the vertical scaling factor, overriding scale"),
+        Arg::with_name("width")
+            .long("width")
+            .takes_value(true)
+            .conflicts_with_all(&["scale-x", "scale-y", "size"])
+            .help("Sets the output width, keeping the aspect ratio if height is not set"),
+        Arg::with_name("height")
+            .long("height")
+            .takes_value(true)
+            .conflicts_with_all(&["scale-x", "scale-y", "size"])
+            .help("Sets the output height, keeping the aspect ratio if width is not set"),
+        Arg::with_name("size")
+            .long("size")
+            .takes_value(true)
+            .conflicts_with_all(&["scale-x", "scale-y"])
+            .help("Sets the output size as WIDTHxHEIGHT or 480p, 720p, 1080p, 1440p, 4k, 8k"),
+        Arg::with_name("mode")
+            .long("mode")
+            .possible_values(&["fit", "fill", "stretch"])
+            .default_value("fit")
+            .help("Sets how the image is matched to the output size when both sides are set"),
+        Arg::with_name("pad")
+            .long("pad")
+            .takes_value(true)
+            .help("Pads a fitted image to the output size with this #RRGGBB[AA] color"),
+        Arg::with_name("iteration")
+            .short("i")
+            .long("iteration")
+            .default_value("1")
+            .help("Sets how many the iteration to do"),
+        Arg::with_name("push-color-strength")
+            .long("pcs")
+            .default_value("0")
+            .help("Sets the push color strength"),
+        Arg::with_name("push-gradient-strength")
+            .long("pgs")
+            .default_value("1")
+            .help("Sets push gradient strength"),
+        Arg::with_name("gradient-gain")
+            .long("gradient-gain")
+            .default_value("1")
+            .help("Sets the gain applied to the gradient magnitude"),
+        Arg::with_name("gradient-threshold")
+            .long("gradient-threshold")
+            .default_value("0")
+            .help("Sets the gradient magnitude (0-255) below which edges are ignored"),
+        Arg::with_name("gradient-gamma")
+            .long("gradient-gamma")
+            .default_value("1")
+            .help("Sets the gamma of the gradient response curve"),
+        Arg::with_name("gradient-knee")
+            .long("gradient-knee")
+            .default_value("0")
+            .help("Sets the soft knee width (0-1) of the gradient response curve"),
+        Arg::with_name("adaptive")
+            .long("adaptive")
+            .help("Scales the push strength per pixel from the local contrast"),
+        Arg::with_name("adaptive-radius")
+            .long("adaptive-radius")
+            .default_value("2")
+            .help("Sets the radius of the window the local contrast is measured over"),
+        Arg::with_name("adaptive-min")
+            .long("adaptive-min")
+            .default_value("0.5")
+            .help("Sets the fraction of the push strength used on flat areas"),
+        Arg::with_name("adaptive-max")
+            .long("adaptive-max")
+            .default_value("1")
+            .help("Sets the fraction of the push strength used on high contrast areas"),
+        Arg::with_name("downscale-filter")
+            .long("downscale-filter")
+            .possible_values(&["area", "lanczos"])
+            .default_value("lanczos")
+            .help("Sets the reduction filter used when scale is below 1"),
+        Arg::with_name("progressive")
+            .long("progressive")
+            .help("Upscales in x2 stages, running the iterations after each stage"),
+        Arg::with_name("stages")
+            .long("stages")
+            .takes_value(true)
+            .conflicts_with("progressive")
+            .help("Upscales in stages of these comma separated factors, e.g. 2,1.5"),
+        Arg::with_name("luma")
+            .long("luma")
+            .possible_values(&["approximate", "bt601", "bt709", "bt2020"])
+            .default_value("approximate")
+            .help("Sets the luma coefficients the push passes decide on"),
+        Arg::with_name("linear")
+            .long("linear")
+            .help("Scales and pushes in linear light instead of on sRGB values"),
+        Arg::with_name("ycbcr")
+            .long("ycbcr")
+            .help("Pushes the luma only, writes planar 4:4:4 YUV if OUTPUT ends in .yuv"),
+        Arg::with_name("chroma-filter")
+            .long("chroma-filter")
+            .possible_values(&["nearest", "bilinear", "bicubic", "lanczos"])
+            .default_value("bicubic")
+            .help("Sets the filter the chroma is scaled with in YCbCr mode"),
+        Arg::with_name("color-blend")
+            .long("color-blend")
+            .possible_values(&["rgb", "oklab", "luma"])
+            .default_value("rgb")
+            .help("Sets the color space push color blends in, oklab and luma keep the hue"),
+    ]
+}
+
+/// Options shared by the single image command and the frames subcommand
+fn options<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let mut options = vec![
+        Arg::with_name("input-format")
+            .long("input-format")
+            .takes_value(true)
+            .possible_values(&INPUT_FORMATS)
+            .help("Sets the input format instead of taking it from the extension or content"),
+        Arg::with_name("output-format")
+            .long("output-format")
+            .takes_value(true)
+            .possible_values(&OUTPUT_FORMATS)
+            .help("Sets the output format instead of taking it from the extension"),
+        Arg::with_name("raw")
+            .long("raw")
+            .takes_value(true)
+            .conflicts_with("input-format")
+            .help("Reads INPUT as headerless WIDTHxHEIGHT:FORMAT[:STRIDE] pixels, FORMAT being rgba8, bgra8 or rgb8"),
+        Arg::with_name("raw-output")
+            .long("raw-output")
+            .takes_value(true)
+            .conflicts_with_all(&["output-format", "palette"])
+            .help("Writes OUTPUT as headerless FORMAT[:STRIDE] pixels, FORMAT being rgba8, bgra8 or rgb8"),
+    ];
+    options.extend(pipeline_options());
+    options.extend(vec![
+        Arg::with_name("quantize")
+            .long("quantize")
+            .possible_values(&["truncate", "round", "bayer", "blue-noise"])
+            .default_value("truncate")
+            .help("Sets how values are taken to the output bit depth, bayer and blue-noise dither"),
+        Arg::with_name("seed")
+            .long("seed")
+            .default_value("0")
+            .help("Sets the seed of the blue noise dither"),
+        Arg::with_name("palette")
+            .long("palette")
+            .takes_value(true)
+            .help(
+                "Writes an indexed PNG with the input palette if set to source, or with this many (2-256) generated colors",
+            ),
+        Arg::with_name("dither")
+            .long("dither")
+            .possible_values(&["none", "ordered", "floyd-steinberg"])
+            .default_value("none")
+            .help("Sets how colors between two palette entries are dithered"),
+        Arg::with_name("quality")
+            .long("quality")
+            .default_value("90")
+            .help("Sets the WebP and AVIF quality from 0 to 100"),
+        Arg::with_name("lossless")
+            .long("lossless")
+            .help("Writes WebP without any loss"),
+        Arg::with_name("avif-speed")
+            .long("avif-speed")
+            .default_value("6")
+            .help("Sets the AVIF encoder speed from 1, smallest, to 10, fastest"),
+        Arg::with_name("webp-method")
+            .long("webp-method")
+            .default_value("4")
+            .help("Sets the WebP compression method from 0, fastest, to 6, smallest"),
+        Arg::with_name("jpeg-quality")
+            .long("jpeg-quality")
+            .default_value("90")
+            .help("Sets the JPEG quality from 1 to 100"),
+        Arg::with_name("jpeg-subsampling")
+            .long("jpeg-subsampling")
+            .possible_values(&["444", "422", "420"])
+            .default_value("420")
+            .help("Sets the JPEG chroma subsampling"),
+        Arg::with_name("jpeg-progressive")
+            .long("jpeg-progressive")
+            .help("Writes progressive JPEG"),
+        Arg::with_name("png-compression")
+            .long("png-compression")
+            .possible_values(&["fast", "default", "best"])
+            .default_value("default")
+            .help("Sets the PNG compression level"),
+        Arg::with_name("png-filter")
+            .long("png-filter")
+            .possible_values(&["none", "sub", "up", "average", "paeth", "adaptive"])
+            .default_value("adaptive")
+            .help("Sets the PNG row filter"),
+        Arg::with_name("background")
+            .long("background")
+            .default_value("#FFFFFF")
+            .help("Sets the #RRGGBB color transparency is laid over for formats without alpha"),
+        Arg::with_name("strip")
+            .long("strip")
+            .help("Leaves the EXIF, text and pixel density of the source out of the output"),
+        Arg::with_name("convert-profile")
+            .long("convert-profile")
+            .takes_value(true)
+            .help(
+                "Converts to srgb, display-p3, adobe-rgb, bt2020 or an ICC file before saving",
+            ),
+    ]);
+    options
+}
 
 fn main() {
-    let matches = App::new("Anime4K-rs")
+    let app = App::new("Anime4K-rs")
         .version("0.1")
         .author("Andra Antariksa <andra.antariksa@gmail.com>")
         .about("A High-Quality Real Time Upscaler for Anime Video")
         .arg(
             Arg::with_name("INPUT")
-                .help("Sets the input file to use")
+                .help("Sets the input file to use, - for stdin")
                 .required(true),
         )
         .arg(
             Arg::with_name("OUTPUT")
-                .help("Sets the output file")
+                .help("Sets the output file, - for stdout")
                 .required(true),
         )
-        .arg(
-            Arg::with_name("scale")
-                .short("s")
-                .long("scale")
-                .default_value("2")
-                .help("Sets the scaling factor"),
+        .args(&options())
+        .subcommand(
+            SubCommand::with_name("frames")
+                .about("Upscales a numbered image sequence, skipping the frames done already")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input frames as a pattern like in/%06d.png")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .help("Sets the output frames as a pattern like out/%06d.png")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .takes_value(true)
+                        .help(
+                        "Sets the first frame number, the first one found from 0 to 4 otherwise",
+                    ),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .long("end")
+                        .takes_value(true)
+                        .help("Sets the last frame number, the last one before a gap otherwise"),
+                )
+                .args(&options()),
         )
-        .arg(
-            Arg::with_name("iteration")
-                .short("i")
-                .long("iteration")
-                .default_value("1")
-                .help("Sets how many the iteration to do"),
+        .subcommand(
+            SubCommand::with_name("video")
+                .about("Upscales a video through ffmpeg, copying the audio and subtitles")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input video")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .help("Sets the output video")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("ffmpeg")
+                        .long("ffmpeg")
+                        .default_value("ffmpeg")
+                        .help("Sets the ffmpeg binary that decodes and encodes"),
+                )
+                .arg(
+                    Arg::with_name("ENCODER_ARGS")
+                        .multiple(true)
+                        .last(true)
+                        .help("Sets the ffmpeg arguments of the video encoder, given after -- [default: -c:v libx264 -crf 18]"),
+                )
+                .args(&pipeline_options()),
         )
-        .arg(
-            Arg::with_name("push-color-strength")
-                .long("pcs")
-                .default_value("0")
-                .help("Sets the push color strength"),
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .get_matches();
+    let frames = app.subcommand_matches("frames");
+    let video = app.subcommand_matches("video");
+    let matches = frames.or(video).unwrap_or(&app);
+
+    let input_filename = matches.value_of("INPUT").unwrap_or_else(|| {
+        exit_with_error("Please specify the input and output files.".to_string())
+    });
+    let output_filename = matches.value_of("OUTPUT").unwrap_or_else(|| {
+        exit_with_error("Please specify the input and output files.".to_string())
+    });
+    let factor = |name: &str| parse_arg(matches, name, pipeline::parse_factor);
+    let scale = factor("scale").unwrap();
+    let scale_x = factor("scale-x").unwrap_or(scale);
+    let scale_y = factor("scale-y").unwrap_or(scale);
+    let (width, height) = match matches.value_of("size") {
+        Some(_) => {
+            let (width, height) = parse_arg(matches, "size", pipeline::parse_size).unwrap();
+            (Some(width), Some(height))
+        }
+        None => (
+            parse_arg(matches, "width", pipeline::parse_side),
+            parse_arg(matches, "height", pipeline::parse_side),
+        ),
+    };
+    let resize = if width.is_some() || height.is_some() {
+        pipeline::Resize::Target {
+            width,
+            height,
+            mode: parse_arg(matches, "mode", str::parse::<pipeline::FitMode>).unwrap(),
+            padding: parse_arg(matches, "pad", pipeline::parse_color),
+        }
+    } else {
+        pipeline::Resize::Factor {
+            x: scale_x,
+            y: scale_y,
+        }
+    };
+    let iteration = parse_arg(matches, "iteration", str::parse::<u32>).unwrap();
+    let push_color_strength = parse_arg(matches, "push-color-strength", str::parse::<f64>).unwrap();
+    let push_gradient_strength =
+        parse_arg(matches, "push-gradient-strength", str::parse::<f64>).unwrap();
+    let gradient_curve = image_kernel::GradientCurve {
+        gain: parse_arg(matches, "gradient-gain", str::parse::<f64>).unwrap(),
+        threshold: parse_arg(matches, "gradient-threshold", str::parse::<f64>).unwrap(),
+        gamma: parse_arg(matches, "gradient-gamma", str::parse::<f64>).unwrap(),
+        knee: parse_arg(matches, "gradient-knee", str::parse::<f64>).unwrap(),
+    };
+    let adaptive = if matches.is_present("adaptive") {
+        Some(image_kernel::AdaptiveStrength {
+            radius: parse_arg(matches, "adaptive-radius", str::parse::<u32>).unwrap(),
+            min: parse_arg(matches, "adaptive-min", str::parse::<f64>).unwrap(),
+            max: parse_arg(matches, "adaptive-max", str::parse::<f64>).unwrap(),
+        })
+    } else {
+        None
+    };
+    let downscale_filter = parse_arg(
+        matches,
+        "downscale-filter",
+        str::parse::<image_kernel::DownscaleFilter>,
+    )
+    .unwrap();
+    let stages = if matches.is_present("stages") {
+        pipeline::Stages::Schedule(parse_arg(matches, "stages", pipeline::parse_stages).unwrap())
+    } else if matches.is_present("progressive") {
+        pipeline::Stages::Step(2.0)
+    } else {
+        pipeline::Stages::Single
+    };
+    let luma_model = parse_arg(matches, "luma", str::parse::<image_kernel::LumaModel>).unwrap();
+
+    let pipeline = pipeline::Pipeline {
+        resize,
+        iteration,
+        push_color_strength,
+        push_gradient_strength,
+        gradient_curve,
+        adaptive,
+        downscale_filter,
+        stages,
+        luma_model,
+        linear: matches.is_present("linear"),
+        ycbcr: matches.is_present("ycbcr"),
+        chroma_filter: parse_arg(
+            matches,
+            "chroma-filter",
+            str::parse::<image_kernel::ChromaFilter>,
         )
-        .arg(
-            Arg::with_name("push-gradient-strength")
-                .long("pgs")
-                .default_value("1")
-                .help("Sets push gradient strength"),
+        .unwrap(),
+        color_blend: parse_arg(
+            matches,
+            "color-blend",
+            str::parse::<image_kernel::ColorBlend>,
         )
-        .get_matches();
+        .unwrap(),
+    };
 
-    let input_filename = matches
-        .value_of("INPUT")
-        .expect("Error: Please specify input and output png files.");
-    let output_filename = matches
-        .value_of("OUTPUT")
-        .expect("Error: Please specify input and output png files.");
-    let scale = matches
-        .value_of("scale")
-        .unwrap()
-        .parse::<f64>()
-        .expect("Error on parsing scale to f64");
-    let iteration = matches
-        .value_of("iteration")
-        .unwrap()
-        .parse::<u8>()
-        .expect("Error on parsing iteration to u8");
-    let push_color_strength = matches
-        .value_of("push-color-strength")
-        .unwrap()
-        .parse::<f64>()
-        .expect("Error on parsing push-color-strength to f64");
-    let push_gradient_strength = matches
-        .value_of("push-gradient-strength")
-        .unwrap()
-        .parse::<f64>()
-        .expect("Error on parsing push-gradient-strength to f64");
-
-    let image = image::open(&input_filename).expect("Can't open image.");
-
-    let mut kernel_instance = image_kernel::ImageKernel::from_image(image);
-    kernel_instance.scale(
-        (kernel_instance.width() as f64 * scale) as u32,
-        (kernel_instance.height() as f64 * scale) as u32,
-    );
-    for _ in 0..iteration {
-        kernel_instance.compute_luminance();
-        kernel_instance.push_color(image_kernel::clamp(
-            (push_color_strength * 255.0) as u16,
-            0,
-            0xFFFF,
-        ));
-        kernel_instance.compute_gradient();
-        kernel_instance.push_gradient(image_kernel::clamp(
-            (push_gradient_strength * 255.0) as u16,
+    if video.is_some() {
+        let ffmpeg = video::Ffmpeg {
+            program: matches.value_of("ffmpeg").unwrap().to_string(),
+            encoder_args: match matches.values_of("ENCODER_ARGS") {
+                Some(args) => args.map(String::from).collect(),
+                None => ["-c:v", "libx264", "-crf", "18"]
+                    .iter()
+                    .map(|arg| arg.to_string())
+                    .collect(),
+            },
+        };
+        ffmpeg
+            .transcode(input_filename, output_filename, &pipeline)
+            .unwrap_or_else(|e| exit_with_error(e));
+        return;
+    }
+
+    let quantization = match parse_arg(
+        matches,
+        "quantize",
+        str::parse::<image_kernel::Quantization>,
+    )
+    .unwrap()
+    {
+        image_kernel::Quantization::BlueNoise(_) => image_kernel::Quantization::BlueNoise(
+            parse_arg(matches, "seed", str::parse::<u64>).unwrap(),
+        ),
+        quantization => quantization,
+    };
+    let dither = parse_arg(matches, "dither", str::parse::<palette::Dither>).unwrap();
+    if dither != palette::Dither::None
+        && matches!(
+            quantization,
+            image_kernel::Quantization::Bayer | image_kernel::Quantization::BlueNoise(_)
+        )
+    {
+        exit_with_error("--dither can't be combined with a dithering --quantize.".to_string());
+    }
+    let palette_colors = match matches.value_of("palette") {
+        Some("source") | None => None,
+        Some(_) => parse_arg(matches, "palette", palette::parse_colors),
+    };
+
+    let encoding = codec::Encoding {
+        quality: clamp(
+            parse_arg(matches, "quality", str::parse::<f32>).unwrap(),
+            0.0,
+            100.0,
+        ),
+        lossless: matches.is_present("lossless"),
+        avif_speed: clamp(
+            parse_arg(matches, "avif-speed", str::parse::<u8>).unwrap(),
+            1,
+            10,
+        ),
+        webp_method: clamp(
+            parse_arg(matches, "webp-method", str::parse::<i32>).unwrap(),
             0,
-            0xFFFF,
+            6,
+        ),
+        jpeg_quality: clamp(
+            parse_arg(matches, "jpeg-quality", str::parse::<u8>).unwrap(),
+            1,
+            100,
+        ),
+        jpeg_subsampling: parse_arg(
+            matches,
+            "jpeg-subsampling",
+            str::parse::<codec::ChromaSubsampling>,
+        )
+        .unwrap(),
+        jpeg_progressive: matches.is_present("jpeg-progressive"),
+        png_compression: parse_arg(
+            matches,
+            "png-compression",
+            str::parse::<codec::PngCompression>,
+        )
+        .unwrap(),
+        png_filter: parse_arg(matches, "png-filter", str::parse::<codec::PngFilter>).unwrap(),
+        background: parse_arg(matches, "background", pipeline::parse_color).unwrap(),
+    };
+
+    let target_profile = parse_arg(matches, "convert-profile", icc::load);
+
+    if frames.is_some()
+        && ["raw", "raw-output", "palette"]
+            .iter()
+            .any(|name| matches.is_present(name))
+    {
+        exit_with_error(
+            "--raw, --raw-output and --palette don't apply to frame sequences.".to_string(),
+        );
+    }
+    if frames.is_some() {
+        let output_format = match matches.value_of("output-format") {
+            Some(format) => image::ImageFormat::from_extension(format),
+            None => image::ImageFormat::from_path(output_filename).ok(),
+        }
+        .unwrap_or_else(|| {
+            exit_with_error(format!(
+                "Can't tell which format to write {} in, use a known extension or set --output-format.",
+                output_filename
+            ))
+        });
+        let sequence = sequence::Sequence {
+            input: input_filename.to_string(),
+            output: output_filename.to_string(),
+            input_format: matches
+                .value_of("input-format")
+                .and_then(image::ImageFormat::from_extension),
+            output_format,
+            start: parse_arg(matches, "start", str::parse::<u64>),
+            end: parse_arg(matches, "end", str::parse::<u64>),
+            strip: matches.is_present("strip"),
+        };
+        // One kernel takes every frame, keeping the settings
+        let mut kernel_instance =
+            image_kernel::ImageKernel::from_image(image::DynamicImage::new_rgba8(1, 1));
+        kernel_instance.quantization = quantization;
+        kernel_instance.encoding = encoding;
+        let progress = sequence
+            .upscale(&mut kernel_instance, &pipeline, target_profile.as_deref())
+            .unwrap_or_else(|e| exit_with_error(e));
+        eprintln!(
+            "Upscaled {} frames, skipped {} done already",
+            progress.upscaled, progress.skipped
+        );
+        return;
+    }
+
+    let y4m_input = match matches.value_of("input-format") {
+        Some(format) => format == "y4m",
+        None => !matches.is_present("raw") && y4m::is_y4m(input_filename),
+    };
+    if y4m_input {
+        if matches.is_present("raw-output") || matches.value_of("output-format").is_some() {
+            exit_with_error("A Y4M stream can only be written as Y4M.".to_string());
+        }
+        if ["strip", "convert-profile", "palette"]
+            .iter()
+            .any(|name| matches.is_present(name))
+            || matches.occurrences_of("quantize") > 0
+        {
+            exit_with_error(
+                "--strip, --convert-profile, --quantize and --palette don't apply to Y4M streams."
+                    .to_string(),
+            );
+        }
+        y4m::upscale(input_filename, output_filename, &pipeline).unwrap_or_else(|e| {
+            exit_with_error(format!("Can't upscale {}: {}", input_filename, e))
+        });
+        return;
+    }
+
+    let raw_output = parse_arg(matches, "raw-output", raw::parse_format);
+    let output_format = match matches.value_of("output-format") {
+        Some(format) => image::ImageFormat::from_extension(format),
+        None if raw_output.is_none() => image::ImageFormat::from_path(output_filename).ok(),
+        None => None,
+    };
+    let yuv_output = pipeline.ycbcr && raw_output.is_none() && output_filename.ends_with(".yuv");
+    if output_format.is_none() && !yuv_output && raw_output.is_none() {
+        exit_with_error(format!(
+            "Can't tell which format to write {} in, use a known extension or set --output-format.",
+            output_filename
         ));
     }
+    if yuv_output && target_profile.is_some() {
+        exit_with_error("YUV output can't be converted with --convert-profile.".to_string());
+    }
+    if yuv_output && matches.occurrences_of("quantize") > 0 {
+        exit_with_error(
+            "YUV output is always rounded, --quantize doesn't apply to it.".to_string(),
+        );
+    }
+    if yuv_output && pipeline.linear {
+        exit_with_error("YUV output can't be written in linear light.".to_string());
+    }
+    if matches.is_present("palette") && output_format != Some(image::ImageFormat::Png) {
+        exit_with_error("Indexed output can only be written as PNG.".to_string());
+    }
+
+    let input_format = matches
+        .value_of("input-format")
+        .and_then(image::ImageFormat::from_extension);
+    let source = match matches.value_of("raw") {
+        Some(_) => {
+            let layout = parse_arg(matches, "raw", str::parse::<raw::RawLayout>).unwrap();
+            raw::read(input_filename, &layout).map(image_kernel::Source::from)
+        }
+        None => image_kernel::read(input_filename, input_format),
+    }
+    .unwrap_or_else(|e| exit_with_error(format!("Can't open {}: {}", input_filename, e)));
+    let metadata = if matches.is_present("strip") {
+        metadata::Metadata::default()
+    } else {
+        source.metadata
+    };
+    if let Some(animation) = &source.animation {
+        let gif = match output_format {
+            _ if yuv_output || raw_output.is_some() => None,
+            Some(image::ImageFormat::Gif) => Some(true),
+            Some(image::ImageFormat::Png) => Some(false),
+            _ => None,
+        }
+        .unwrap_or_else(|| {
+            exit_with_error(format!(
+                "{} is animated, which can only be written as GIF or PNG.",
+                input_filename
+            ))
+        });
+        if matches.is_present("palette") {
+            exit_with_error("--palette doesn't apply to animations.".to_string());
+        }
+        if gif && target_profile.is_some() {
+            exit_with_error(
+                "GIF can't carry an ICC profile, --convert-profile doesn't apply to it."
+                    .to_string(),
+            );
+        }
+        // One kernel takes every frame, keeping the settings
+        let mut kernel_instance =
+            image_kernel::ImageKernel::from_image(image::DynamicImage::new_rgba8(1, 1));
+        kernel_instance.icc_profile = source.icc_profile.clone();
+        kernel_instance.quantization = quantization;
+        kernel_instance.encoding = encoding;
+        let upscaled = animation
+            .upscale(&mut kernel_instance, &pipeline, target_profile.as_deref())
+            .unwrap_or_else(|e| exit_with_error(format!("Can't convert the colors: {}", e)));
+        let result = if gif {
+            upscaled.write_gif(output_filename, dither)
+        } else {
+            upscaled.write_apng(
+                output_filename,
+                kernel_instance.icc_profile.as_deref(),
+                &metadata,
+                &kernel_instance.encoding,
+            )
+        };
+        result
+            .unwrap_or_else(|e| exit_with_error(format!("Can't save {}: {}", output_filename, e)));
+        return;
+    }
+    if pipeline.runs_luma(source.image.color())
+        && target_profile.is_none()
+        && !matches.is_present("palette")
+        && raw_output.is_none()
+    {
+        // Grayscale runs as a single plane, a quarter of the width of RGBA,
+        // and is saved as grayscale
+        let mut kernel_instance = image_kernel::ImageKernel::from_luma_image(source.image);
+        kernel_instance.icc_profile = source.icc_profile;
+        kernel_instance.metadata = metadata;
+        kernel_instance.quantization = quantization;
+        kernel_instance.encoding = encoding;
+        pipeline.run_luma(&mut kernel_instance);
+        kernel_instance
+            .save(output_filename, output_format)
+            .unwrap_or_else(|e| exit_with_error(format!("Can't save {}: {}", output_filename, e)));
+        return;
+    }
+
+    let mut kernel_instance = image_kernel::ImageKernel::from_image(source.image);
+    kernel_instance.icc_profile = source.icc_profile;
+    kernel_instance.metadata = metadata;
+    kernel_instance.quantization = quantization;
+    kernel_instance.encoding = encoding;
+    if yuv_output {
+        pipeline
+            .run_ycbcr(&mut kernel_instance)
+            .save_yuv(output_filename)
+            .unwrap_or_else(|e| exit_with_error(format!("Can't save {}: {}", output_filename, e)));
+        return;
+    }
+    pipeline.run(&mut kernel_instance);
+    if let Some(target_profile) = target_profile {
+        kernel_instance
+            .convert_profile(&target_profile)
+            .unwrap_or_else(|e| exit_with_error(format!("Can't convert the colors: {}", e)));
+    }
+    if let Some((format, stride)) = raw_output {
+        kernel_instance
+            .save_raw(output_filename, format, stride)
+            .unwrap_or_else(|e| exit_with_error(format!("Can't save {}: {}", output_filename, e)));
+        return;
+    }
+    if matches.is_present("palette") {
+        let palette = match palette_colors {
+            Some(colors) => palette::Palette::generate(&kernel_instance.to_rgba8(), colors),
+            None => source.palette.unwrap_or_else(|| {
+                exit_with_error(format!(
+                    "{} has no palette to re-quantize to.",
+                    input_filename
+                ))
+            }),
+        };
+        kernel_instance
+            .save_indexed(output_filename, &palette, dither)
+            .unwrap_or_else(|e| exit_with_error(format!("Can't save {}: {}", output_filename, e)));
+        return;
+    }
     kernel_instance
-        .save(output_filename)
-        .expect("Can't save image.");
+        .save(output_filename, output_format)
+        .unwrap_or_else(|e| exit_with_error(format!("Can't save {}: {}", output_filename, e)));
 }
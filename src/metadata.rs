@@ -0,0 +1,280 @@
+use img_parts::jpeg::{markers, Jpeg, JpegSegment};
+use img_parts::png::{Png, PngChunk};
+use img_parts::{Bytes, DynImage, ImageEXIF};
+
+const ORIENTATION: u16 = 0x0112;
+const EXIF_IFD: u16 = 0x8769;
+const PIXEL_WIDTH: u16 = 0xA002;
+const PIXEL_HEIGHT: u16 = 0xA003;
+const TEXT_CHUNKS: [[u8; 4]; 3] = [*b"tEXt", *b"zTXt", *b"iTXt"];
+const METERS_PER_INCH: f64 = 0.0254;
+
+/// What a source carries besides its pixels and ICC profile
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    /// EXIF block in TIFF layout
+    pub exif: Option<Vec<u8>>,
+    /// The tEXt, zTXt and iTXt chunks of a PNG, as their type and contents
+    pub text: Vec<([u8; 4], Vec<u8>)>,
+    /// Horizontal and vertical pixels per inch
+    pub density: Option<(f64, f64)>,
+    /// Size of the image the density was given for
+    pub size: (u32, u32),
+}
+
+impl Metadata {
+    /// Reads the EXIF, text and density of an encoded PNG, JPEG or WebP file
+    pub fn read(encoded: &[u8], size: (u32, u32)) -> Metadata {
+        let bytes = Bytes::copy_from_slice(encoded);
+        let mut metadata = Metadata {
+            size,
+            ..Metadata::default()
+        };
+        if let Ok(Some(image)) = DynImage::from_bytes(bytes.clone()) {
+            metadata.exif = image.exif().map(|exif| exif.to_vec());
+        }
+        match image::guess_format(encoded) {
+            Ok(image::ImageFormat::Png) => {
+                if let Ok(png) = Png::from_bytes(bytes) {
+                    metadata.text = png
+                        .chunks()
+                        .iter()
+                        .filter(|chunk| TEXT_CHUNKS.contains(&chunk.kind()))
+                        .map(|chunk| (chunk.kind(), chunk.contents().to_vec()))
+                        .collect();
+                    metadata.density = png
+                        .chunk_by_type(*b"pHYs")
+                        .and_then(|chunk| read_phys(chunk.contents()));
+                }
+            }
+            Ok(image::ImageFormat::Jpeg) => {
+                if let Ok(jpeg) = Jpeg::from_bytes(bytes) {
+                    metadata.density = jpeg
+                        .segments_by_marker(markers::APP0)
+                        .find_map(|segment| read_jfif(segment.contents()));
+                }
+            }
+            _ => {}
+        }
+        metadata
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.text.is_empty() && self.density.is_none()
+    }
+
+    /// Turns `image` upright according to the EXIF orientation, which is then
+    /// reset so that viewers don't turn it a second time
+    pub fn orient(&mut self, image: image::DynamicImage) -> image::DynamicImage {
+        let orientation = self
+            .exif
+            .as_deref()
+            .and_then(|exif| read_tag(exif, ORIENTATION))
+            .unwrap_or(1);
+        let image = match orientation {
+            2 => image.fliph(),
+            3 => image.rotate180(),
+            4 => image.flipv(),
+            5 => image.rotate90().fliph(),
+            6 => image.rotate90(),
+            7 => image.rotate270().fliph(),
+            8 => image.rotate270(),
+            _ => return image,
+        };
+        if orientation >= 5 {
+            self.density = self.density.map(|(x, y)| (y, x));
+            self.size = (self.size.1, self.size.0);
+        }
+        if let Some(exif) = &mut self.exif {
+            write_tag(exif, ORIENTATION, 1);
+        }
+        image
+    }
+
+    /// Adds the metadata to an encoded PNG, JPEG or WebP file of `size`. The
+    /// density is scaled along so that the printed size stays the same. Other
+    /// formats are returned untouched.
+    pub fn embed(&self, encoded: Vec<u8>, size: (u32, u32)) -> Vec<u8> {
+        if self.is_empty() {
+            return encoded;
+        }
+        let density = self.density.map(|(x, y)| {
+            (
+                x * size.0 as f64 / self.size.0.max(1) as f64,
+                y * size.1 as f64 / self.size.1.max(1) as f64,
+            )
+        });
+        let exif = self.exif.clone().map(|mut exif| {
+            write_tag(&mut exif, PIXEL_WIDTH, size.0);
+            write_tag(&mut exif, PIXEL_HEIGHT, size.1);
+            Bytes::from(exif)
+        });
+
+        match image::guess_format(&encoded) {
+            Ok(image::ImageFormat::Png) => {
+                let mut png = match Png::from_bytes(Bytes::from(encoded.clone())) {
+                    Ok(png) => png,
+                    Err(_) => return encoded,
+                };
+                let mut chunks = Vec::new();
+                if let Some(exif) = exif {
+                    chunks.push(PngChunk::new(*b"eXIf", exif));
+                }
+                if let Some((x, y)) = density {
+                    let mut phys = Vec::with_capacity(9);
+                    phys.extend_from_slice(&((x / METERS_PER_INCH).round() as u32).to_be_bytes());
+                    phys.extend_from_slice(&((y / METERS_PER_INCH).round() as u32).to_be_bytes());
+                    phys.push(1);
+                    chunks.push(PngChunk::new(*b"pHYs", Bytes::from(phys)));
+                }
+                for (kind, contents) in &self.text {
+                    chunks.push(PngChunk::new(*kind, Bytes::copy_from_slice(contents)));
+                }
+                // pHYs and eXIf have to come before the image data
+                let data = png
+                    .chunks()
+                    .iter()
+                    .position(|chunk| chunk.kind() == *b"IDAT")
+                    .unwrap_or(1);
+                png.chunks_mut().splice(data..data, chunks);
+                png.encoder().bytes().to_vec()
+            }
+            Ok(image::ImageFormat::Jpeg) => {
+                let mut jpeg = match Jpeg::from_bytes(Bytes::from(encoded.clone())) {
+                    Ok(jpeg) => jpeg,
+                    Err(_) => return encoded,
+                };
+                if let Some((x, y)) = density {
+                    let clamp =
+                        |value: f64| crate::image_kernel::clamp(value.round(), 1.0, 65535.0);
+                    let mut jfif = b"JFIF\0\x01\x02\x01".to_vec();
+                    jfif.extend_from_slice(&(clamp(x) as u16).to_be_bytes());
+                    jfif.extend_from_slice(&(clamp(y) as u16).to_be_bytes());
+                    jfif.extend_from_slice(&[0, 0]);
+                    let segment = JpegSegment::new_with_contents(markers::APP0, Bytes::from(jfif));
+                    let segments = jpeg.segments_mut();
+                    match segments.iter().position(is_jfif) {
+                        Some(i) => segments[i] = segment,
+                        None => segments.insert(0, segment),
+                    }
+                }
+                if exif.is_some() {
+                    jpeg.set_exif(exif);
+                }
+                jpeg.encoder().bytes().to_vec()
+            }
+            Ok(image::ImageFormat::WebP) => {
+                match DynImage::from_bytes(Bytes::from(encoded.clone())) {
+                    Ok(Some(mut image)) if exif.is_some() => {
+                        image.set_exif(exif);
+                        image.encoder().bytes().to_vec()
+                    }
+                    _ => encoded,
+                }
+            }
+            _ => encoded,
+        }
+    }
+}
+
+fn is_jfif(segment: &JpegSegment) -> bool {
+    segment.marker() == markers::APP0 && segment.contents().starts_with(b"JFIF\0")
+}
+
+/// Pixels per inch of a pHYs chunk, if its unit is the meter
+fn read_phys(contents: &[u8]) -> Option<(f64, f64)> {
+    if contents.len() != 9 || contents[8] != 1 {
+        return None;
+    }
+    let x = u32::from_be_bytes([contents[0], contents[1], contents[2], contents[3]]);
+    let y = u32::from_be_bytes([contents[4], contents[5], contents[6], contents[7]]);
+    Some((x as f64 * METERS_PER_INCH, y as f64 * METERS_PER_INCH))
+}
+
+/// Pixels per inch of a JFIF APP0 segment, if it gives a unit
+fn read_jfif(contents: &[u8]) -> Option<(f64, f64)> {
+    if contents.len() < 12 || !contents.starts_with(b"JFIF\0") {
+        return None;
+    }
+    let x = u16::from_be_bytes([contents[8], contents[9]]) as f64;
+    let y = u16::from_be_bytes([contents[10], contents[11]]) as f64;
+    match contents[7] {
+        1 => Some((x, y)),
+        2 => Some((x * 2.54, y * 2.54)),
+        _ => None,
+    }
+}
+
+fn read_uint(exif: &[u8], offset: usize, len: usize, big_endian: bool) -> Option<u32> {
+    let bytes = exif.get(offset..offset + len)?;
+    let fold = |value: u32, &byte: &u8| value << 8 | byte as u32;
+    Some(if big_endian {
+        bytes.iter().fold(0, fold)
+    } else {
+        bytes.iter().rev().fold(0, fold)
+    })
+}
+
+fn write_uint(exif: &mut [u8], offset: usize, len: usize, big_endian: bool, value: u32) {
+    for i in 0..len {
+        let shift = if big_endian { len - 1 - i } else { i } * 8;
+        exif[offset + i] = (value >> shift) as u8;
+    }
+}
+
+/// Offsets of the entries of the first IFD and of the Exif IFD it points to,
+/// and whether the block is big endian
+fn exif_entries(exif: &[u8]) -> (Vec<usize>, bool) {
+    let big_endian = exif.starts_with(b"MM");
+    if !big_endian && !exif.starts_with(b"II") {
+        return (Vec::new(), big_endian);
+    }
+    let ifd = |offset: u32| -> Vec<usize> {
+        let offset = offset as usize;
+        let count = read_uint(exif, offset, 2, big_endian).unwrap_or(0) as usize;
+        (0..count)
+            .map(|i| offset + 2 + i * 12)
+            .take_while(|&entry| entry + 12 <= exif.len())
+            .collect()
+    };
+
+    let mut entries = read_uint(exif, 4, 4, big_endian).map_or_else(Vec::new, ifd);
+    let sub_ifd = entries
+        .iter()
+        .find(|&&entry| read_uint(exif, entry, 2, big_endian) == Some(EXIF_IFD as u32))
+        .and_then(|&entry| read_uint(exif, entry + 8, 4, big_endian));
+    if let Some(offset) = sub_ifd {
+        entries.extend(ifd(offset));
+    }
+    (entries, big_endian)
+}
+
+/// Offset and byte length of the value of a SHORT or LONG tag
+fn find_tag(exif: &[u8], tag: u16) -> Option<(usize, usize, bool)> {
+    let (entries, big_endian) = exif_entries(exif);
+    let entry = entries
+        .into_iter()
+        .find(|&entry| read_uint(exif, entry, 2, big_endian) == Some(tag as u32))?;
+    match read_uint(exif, entry + 2, 2, big_endian)? {
+        3 => Some((entry + 8, 2, big_endian)),
+        4 => Some((entry + 8, 4, big_endian)),
+        _ => None,
+    }
+}
+
+fn read_tag(exif: &[u8], tag: u16) -> Option<u32> {
+    let (offset, len, big_endian) = find_tag(exif, tag)?;
+    read_uint(exif, offset, len, big_endian)
+}
+
+/// Overwrites a SHORT or LONG tag in place, if the block has it
+fn write_tag(exif: &mut [u8], tag: u16, value: u32) {
+    if let Some((offset, len, big_endian)) = find_tag(exif, tag) {
+        let value = if len == 2 {
+            value.min(u16::MAX as u32)
+        } else {
+            value
+        };
+        write_uint(exif, offset, len, big_endian, value);
+    }
+}
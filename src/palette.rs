@@ -0,0 +1,264 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+/// Normalized 8x8 Bayer matrix, centered on zero
+pub fn bayer(x: u32, y: u32) -> f32 {
+    const BAYER: [[u8; 8]; 8] = [
+        [0, 32, 8, 40, 2, 34, 10, 42],
+        [48, 16, 56, 24, 50, 18, 58, 26],
+        [12, 44, 4, 36, 14, 46, 6, 38],
+        [60, 28, 52, 20, 62, 30, 54, 22],
+        [3, 35, 11, 43, 1, 33, 9, 41],
+        [51, 19, 59, 27, 49, 17, 57, 25],
+        [15, 47, 7, 39, 13, 45, 5, 37],
+        [63, 31, 55, 23, 61, 29, 53, 21],
+    ];
+    (BAYER[(y % 8) as usize][(x % 8) as usize] as f32 + 0.5) / 64.0 - 0.5
+}
+
+/// How the colors that fall between two palette entries are spread
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dither {
+    /// Takes the nearest entry
+    None,
+    /// Offsets every pixel by an 8x8 Bayer threshold first
+    Ordered,
+    /// Carries the error of every pixel over to its neighbours (Floyd-Steinberg)
+    ErrorDiffusion,
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Dither, String> {
+        match s {
+            "none" => Ok(Dither::None),
+            "ordered" => Ok(Dither::Ordered),
+            "floyd-steinberg" => Ok(Dither::ErrorDiffusion),
+            _ => Err(format!("Unknown dither {}", s)),
+        }
+    }
+}
+
+/// Parses the color count of a generated palette, from 2 to 256
+pub fn parse_colors(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(colors) if (2..=256).contains(&colors) => Ok(colors),
+        _ => Err(format!(
+            "Invalid palette {}, expected source or 2 to 256 colors",
+            s
+        )),
+    }
+}
+
+/// Up to 256 RGBA colors of an indexed image
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub colors: Vec<[u8; 4]>,
+}
+
+impl Palette {
+    /// Reads the palette of an indexed PNG or a GIF, `None` for anything else
+    pub fn read(encoded: &[u8]) -> Option<Palette> {
+        match image::guess_format(encoded).ok()? {
+            image::ImageFormat::Png => {
+                let reader = png::Decoder::new(encoded).read_info().ok()?;
+                let info = reader.info();
+                if info.color_type != png::ColorType::Indexed {
+                    return None;
+                }
+                let alpha = info.trns.as_deref().unwrap_or(&[]);
+                Some(Palette::from_rgb(info.palette.as_deref()?, |i| {
+                    alpha.get(i).cloned().unwrap_or(0xFF)
+                }))
+            }
+            image::ImageFormat::Gif => {
+                let mut decoder = gif::DecodeOptions::new().read_info(encoded).ok()?;
+                let global = decoder.global_palette().map(|palette| palette.to_vec());
+                let frame = decoder.read_next_frame().ok()??;
+                let transparent = frame.transparent.map(|i| i as usize);
+                let colors = frame.palette.clone().or(global)?;
+                Some(Palette::from_rgb(&colors, |i| {
+                    if Some(i) == transparent {
+                        0
+                    } else {
+                        0xFF
+                    }
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    fn from_rgb<F: Fn(usize) -> u8>(rgb: &[u8], alpha: F) -> Palette {
+        Palette {
+            colors: rgb
+                .chunks_exact(3)
+                .enumerate()
+                .map(|(i, color)| [color[0], color[1], color[2], alpha(i)])
+                .collect(),
+        }
+    }
+
+    /// Picks `colors` entries that best cover `image`, exactly when it has no
+    /// more colors than that and with NeuQuant otherwise
+    pub fn generate(image: &image::RgbaImage, colors: usize) -> Palette {
+        let mut seen = HashSet::new();
+        let mut exact = Vec::new();
+        let fits = image.pixels().all(|pixel| {
+            if seen.insert(pixel.0) {
+                exact.push(pixel.0);
+            }
+            exact.len() <= colors
+        });
+        if fits {
+            return Palette { colors: exact };
+        }
+
+        let quantizer = color_quant::NeuQuant::new(10, colors, image.as_raw());
+        Palette {
+            colors: quantizer
+                .color_map_rgba()
+                .chunks_exact(4)
+                .map(|color| [color[0], color[1], color[2], color[3]])
+                .collect(),
+        }
+    }
+
+    fn nearest(&self, color: [u8; 4]) -> u8 {
+        let distance = |entry: &[u8; 4]| {
+            entry
+                .iter()
+                .zip(color.iter())
+                .map(|(&a, &b)| (a as i32 - b as i32).pow(2))
+                .sum::<i32>()
+        };
+        (0..self.colors.len())
+            .min_by_key(|&i| distance(&self.colors[i]))
+            .unwrap_or(0) as u8
+    }
+
+    /// Average step from an entry to its closest other entry, the amplitude
+    /// an ordered dither needs to reach the next color
+    fn spacing(&self) -> f32 {
+        let steps: Vec<i32> = self
+            .colors
+            .iter()
+            .enumerate()
+            .filter_map(|(i, a)| {
+                self.colors
+                    .iter()
+                    .enumerate()
+                    .filter(|&(j, b)| j != i && b != a)
+                    .map(|(_, b)| {
+                        (0..3)
+                            .map(|c| (a[c] as i32 - b[c] as i32).abs())
+                            .max()
+                            .unwrap()
+                    })
+                    .min()
+            })
+            .collect();
+        if steps.is_empty() {
+            0.0
+        } else {
+            steps.iter().sum::<i32>() as f32 / steps.len() as f32
+        }
+    }
+
+    /// Maps every pixel of `image` to the index of a palette entry
+    pub fn quantize(&self, image: &image::RgbaImage, dither: Dither) -> Vec<u8> {
+        let (width, height) = image.dimensions();
+        let mut cache = HashMap::new();
+        let mut lookup =
+            |color: [u8; 4]| *cache.entry(color).or_insert_with(|| self.nearest(color));
+        let to_u8 = |value: f32| clamp_u8(value.round());
+
+        let mut indices = Vec::with_capacity((width * height) as usize);
+        match dither {
+            Dither::None => {
+                for pixel in image.pixels() {
+                    indices.push(lookup(pixel.0));
+                }
+            }
+            Dither::Ordered => {
+                let spread = self.spacing();
+                for (x, y, pixel) in image.enumerate_pixels() {
+                    let offset = bayer(x, y) * spread;
+                    let mut color = pixel.0;
+                    for value in color.iter_mut().take(3) {
+                        *value = to_u8(*value as f32 + offset);
+                    }
+                    indices.push(lookup(color));
+                }
+            }
+            Dither::ErrorDiffusion => {
+                let mut values: Vec<f32> = image.as_raw().iter().map(|&v| v as f32).collect();
+                for y in 0..height {
+                    for x in 0..width {
+                        let i = ((y * width + x) * 4) as usize;
+                        let mut color = [0u8; 4];
+                        for (c, value) in color.iter_mut().enumerate() {
+                            *value = to_u8(values[i + c]);
+                        }
+                        let index = lookup(color);
+                        indices.push(index);
+
+                        let entry = self.colors[index as usize];
+                        for c in 0..4 {
+                            let error = values[i + c] - entry[c] as f32;
+                            let mut spread = |dx: i64, dy: u32, weight: f32| {
+                                let nx = x as i64 + dx;
+                                let ny = y + dy;
+                                if nx >= 0 && nx < width as i64 && ny < height {
+                                    let j = ((ny * width + nx as u32) * 4) as usize + c;
+                                    values[j] += error * weight;
+                                }
+                            };
+                            spread(1, 0, 7.0 / 16.0);
+                            spread(-1, 1, 3.0 / 16.0);
+                            spread(0, 1, 5.0 / 16.0);
+                            spread(1, 1, 1.0 / 16.0);
+                        }
+                    }
+                }
+            }
+        }
+        indices
+    }
+
+    /// Writes `indices` as an 8 bit indexed PNG
+    pub fn encode_png(
+        &self,
+        width: u32,
+        height: u32,
+        indices: &[u8],
+    ) -> Result<Vec<u8>, png::EncodingError> {
+        let mut encoded = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut encoded, width, height);
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_palette(
+                self.colors
+                    .iter()
+                    .flat_map(|color| color[..3].to_vec())
+                    .collect::<Vec<u8>>(),
+            );
+            if self.colors.iter().any(|color| color[3] != 0xFF) {
+                encoder.set_trns(
+                    self.colors
+                        .iter()
+                        .map(|color| color[3])
+                        .collect::<Vec<u8>>(),
+                );
+            }
+            encoder.write_header()?.write_image_data(indices)?;
+        }
+        Ok(encoded)
+    }
+}
+
+fn clamp_u8(value: f32) -> u8 {
+    crate::image_kernel::clamp(value, 0.0, 255.0) as u8
+}
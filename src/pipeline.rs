@@ -0,0 +1,415 @@
+use crate::image_kernel::{
+    self, AdaptiveStrength, ChromaFilter, ColorBlend, DownscaleFilter, GradientCurve, ImageKernel,
+    KernelPixel, LumaModel, YCbCrKernel,
+};
+use std::cmp::{max, min};
+use std::str::FromStr;
+
+/// Parses a `WIDTHxHEIGHT` size or one of the named video frame sizes
+pub fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    match s.to_lowercase().as_str() {
+        "480p" => Ok((854, 480)),
+        "720p" => Ok((1280, 720)),
+        "1080p" => Ok((1920, 1080)),
+        "1440p" => Ok((2560, 1440)),
+        "4k" | "2160p" => Ok((3840, 2160)),
+        "8k" | "4320p" => Ok((7680, 4320)),
+        size => {
+            let mut parts = size.splitn(2, 'x');
+            match (parts.next().map(parse_side), parts.next().map(parse_side)) {
+                (Some(Ok(width)), Some(Ok(height))) => Ok((width, height)),
+                _ => Err(format!("Invalid size {}", s)),
+            }
+        }
+    }
+}
+
+/// Parses one side of an output size, which needs at least one pixel
+pub fn parse_side(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(side) if side > 0 => Ok(side),
+        _ => Err(format!("Invalid size {}", s)),
+    }
+}
+
+/// Parses a scaling factor, which has to be finite and above 0
+pub fn parse_factor(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(factor),
+        _ => Err(format!("Invalid factor {}", s)),
+    }
+}
+
+/// Parses the comma separated factors of a stage schedule
+pub fn parse_stages(s: &str) -> Result<Vec<f64>, String> {
+    s.split(',').map(parse_factor).collect()
+}
+
+/// Parses a `#RRGGBB` or `#RRGGBBAA` color, the `#` being optional
+pub fn parse_color(s: &str) -> Result<image::Rgba<u8>, String> {
+    let hex = s.trim_start_matches('#');
+    if (hex.len() != 6 && hex.len() != 8) || !hex.is_ascii() {
+        return Err(format!("Invalid color {}", s));
+    }
+    let mut color = image::Rgba::<u8>([0, 0, 0, 0xFF]);
+    for (i, channel) in color.0.iter_mut().take(hex.len() / 2).enumerate() {
+        *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("Invalid color {}", s))?;
+    }
+    Ok(color)
+}
+
+/// How an image is made to match a target frame when both of its sides are given
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FitMode {
+    /// Scales uniformly until the image fits inside the frame
+    Fit,
+    /// Scales uniformly until the image covers the frame, cropping the overflow
+    Fill,
+    /// Scales each side to the frame, ignoring the aspect ratio
+    Stretch,
+}
+
+impl FromStr for FitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FitMode, String> {
+        match s {
+            "fit" => Ok(FitMode::Fit),
+            "fill" => Ok(FitMode::Fill),
+            "stretch" => Ok(FitMode::Stretch),
+            _ => Err(format!("Unknown fit mode {}", s)),
+        }
+    }
+}
+
+/// How the output size is derived from the input size
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resize {
+    /// Multiplies each side by its own factor
+    Factor { x: f64, y: f64 },
+    /// Scales to a target width and/or height, a missing side keeps the aspect ratio
+    Target {
+        width: Option<u32>,
+        height: Option<u32>,
+        mode: FitMode,
+        /// Pads a fitted image up to the full frame with this color
+        padding: Option<image::Rgba<u8>>,
+    },
+}
+
+impl Resize {
+    /// Returns the size the image is scaled to and the size of the final frame
+    pub fn layout(&self, width: u32, height: u32) -> ((u32, u32), (u32, u32)) {
+        let scaled = |factor_x: f64, factor_y: f64| {
+            (
+                max((width as f64 * factor_x).round() as u32, 1),
+                max((height as f64 * factor_y).round() as u32, 1),
+            )
+        };
+
+        match *self {
+            Resize::Factor { x, y } => {
+                let size = scaled(x, y);
+                (size, size)
+            }
+            Resize::Target {
+                width: target_width,
+                height: target_height,
+                mode,
+                padding,
+            } => match (target_width, target_height) {
+                (None, None) => ((width, height), (width, height)),
+                (Some(target_width), None) => {
+                    let factor = target_width as f64 / width as f64;
+                    let size = (target_width, scaled(factor, factor).1);
+                    (size, size)
+                }
+                (None, Some(target_height)) => {
+                    let factor = target_height as f64 / height as f64;
+                    let size = (scaled(factor, factor).0, target_height);
+                    (size, size)
+                }
+                (Some(target_width), Some(target_height)) => {
+                    let frame = (target_width, target_height);
+                    let factor_x = target_width as f64 / width as f64;
+                    let factor_y = target_height as f64 / height as f64;
+                    match mode {
+                        FitMode::Stretch => (frame, frame),
+                        FitMode::Fit => {
+                            let factor = factor_x.min(factor_y);
+                            let size = scaled(factor, factor);
+                            let size = (min(size.0, target_width), min(size.1, target_height));
+                            (size, padding.map_or(size, |_| frame))
+                        }
+                        FitMode::Fill => {
+                            let factor = factor_x.max(factor_y);
+                            let size = scaled(factor, factor);
+                            let size = (max(size.0, target_width), max(size.1, target_height));
+                            (size, frame)
+                        }
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// How an upscale is split into stages, each one followed by the push passes
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stages {
+    /// Scales straight to the output size
+    Single,
+    /// Scales by this factor until the output size is near, then by the remainder
+    Step(f64),
+    /// Scales by each of these factors in order, then by the remainder
+    Schedule(Vec<f64>),
+}
+
+impl Stages {
+    /// Returns the factor of every stage, their product being `total`
+    pub fn factors(&self, total: f64) -> Vec<f64> {
+        // Remainders this close to 1 are rounding noise rather than a stage
+        let epsilon = 1e-3;
+        let schedule: Box<dyn Iterator<Item = f64>> = match self {
+            Stages::Single => Box::new(std::iter::empty()),
+            Stages::Step(step) => Box::new(std::iter::repeat(*step)),
+            Stages::Schedule(factors) => Box::new(factors.clone().into_iter()),
+        };
+
+        let mut factors = Vec::new();
+        let mut remainder = total;
+        for factor in schedule {
+            if factor <= 1.0 || factor >= remainder - epsilon {
+                break;
+            }
+            factors.push(factor);
+            remainder /= factor;
+        }
+        if remainder > 1.0 + epsilon || factors.is_empty() {
+            factors.push(remainder);
+        } else {
+            // Fold the leftover noise into the last stage
+            *factors.last_mut().unwrap() *= remainder;
+        }
+        factors
+    }
+}
+
+/// Settings of a whole run, from the scaling to the last push pass
+#[derive(Clone, Debug)]
+pub struct Pipeline {
+    pub resize: Resize,
+    pub iteration: u32,
+    pub push_color_strength: f64,
+    pub push_gradient_strength: f64,
+    pub gradient_curve: GradientCurve,
+    pub adaptive: Option<AdaptiveStrength>,
+    pub downscale_filter: DownscaleFilter,
+    pub stages: Stages,
+    pub luma_model: LumaModel,
+    /// Decodes sRGB to linear light for the scaling and the push blends
+    pub linear: bool,
+    /// Runs the passes on luma only and scales the chroma with `chroma_filter`
+    pub ycbcr: bool,
+    pub chroma_filter: ChromaFilter,
+    pub color_blend: ColorBlend,
+}
+
+impl Default for Pipeline {
+    fn default() -> Pipeline {
+        Pipeline {
+            resize: Resize::Factor { x: 2.0, y: 2.0 },
+            iteration: 1,
+            push_color_strength: 0.0,
+            push_gradient_strength: 1.0,
+            gradient_curve: GradientCurve::default(),
+            adaptive: None,
+            downscale_filter: DownscaleFilter::Lanczos,
+            stages: Stages::Single,
+            luma_model: LumaModel::default(),
+            linear: false,
+            ycbcr: false,
+            chroma_filter: ChromaFilter::Bicubic,
+            color_blend: ColorBlend::default(),
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn run(&self, kernel: &mut ImageKernel) {
+        self.run_with(kernel, |kernel| {
+            if self.ycbcr {
+                *kernel = self.run_ycbcr(kernel).to_rgba();
+            } else {
+                self.process_frame(kernel);
+            }
+        });
+    }
+
+    /// Whether images of `color` run as a single plane through `run_luma`.
+    /// That is grayscale without alpha, which the plane has no room for.
+    pub fn runs_luma(&self, color: image::ColorType) -> bool {
+        !color.has_color() && !color.has_alpha() && !self.ycbcr
+    }
+
+    /// Runs the passes on a grayscale kernel, where the pixel is its own luma
+    pub fn run_luma(&self, kernel: &mut ImageKernel<image::Luma<f32>>) {
+        self.run_with(kernel, |kernel| self.process_frame(kernel));
+    }
+
+    /// Runs `f` in linear light when asked to
+    fn run_with<P: KernelPixel, F: FnOnce(&mut ImageKernel<P>)>(
+        &self,
+        kernel: &mut ImageKernel<P>,
+        f: F,
+    ) {
+        kernel.luma_model = self.luma_model;
+        let decode = self.linear && !kernel.linear;
+        if decode {
+            kernel.decode_srgb();
+        }
+
+        f(kernel);
+
+        if decode {
+            kernel.encode_srgb();
+        }
+    }
+
+    /// Runs the passes on luma only, the chroma planes are just scaled to match
+    pub fn run_ycbcr(&self, kernel: &mut ImageKernel) -> YCbCrKernel {
+        kernel.luma_model = self.luma_model;
+        let mut planes = kernel.to_ycbcr();
+
+        let frame = self.process(&mut planes.luma);
+        planes.scale_chroma(self.chroma_filter);
+        if let Some((frame_width, frame_height)) = frame {
+            planes.reframe(frame_width, frame_height, self.background());
+        }
+        planes
+    }
+
+    /// Scales a mask, like the alpha channel the passes overwrite, to the
+    /// frame `run` gives
+    pub fn scale_mask(&self, mask: &mut ImageKernel<image::Luma<f32>>) {
+        let ((width, height), (frame_width, frame_height)) =
+            self.resize.layout(mask.width(), mask.height());
+        mask.scale(width, height);
+        if (frame_width, frame_height) != (width, height) {
+            let alpha = self.background()[3];
+            mask.reframe(
+                frame_width,
+                frame_height,
+                image::Rgba([alpha, alpha, alpha, alpha]),
+            );
+        }
+    }
+
+    /// Padding color in the working color space
+    fn background(&self) -> image::Rgba<f32> {
+        let padding = match self.resize {
+            Resize::Target {
+                padding: Some(padding),
+                ..
+            } => padding,
+            _ => image::Rgba::<u8>([0, 0, 0, 0]),
+        };
+        let mut background = image::Rgba::<f32>([
+            padding[0] as f32 / 255.0,
+            padding[1] as f32 / 255.0,
+            padding[2] as f32 / 255.0,
+            padding[3] as f32 / 255.0,
+        ]);
+        if self.linear {
+            for value in background.0.iter_mut().take(3) {
+                *value = image_kernel::srgb_to_linear(*value);
+            }
+        }
+        background
+    }
+
+    fn process_frame<P: KernelPixel>(&self, kernel: &mut ImageKernel<P>) {
+        if let Some((frame_width, frame_height)) = self.process(kernel) {
+            kernel.reframe(frame_width, frame_height, self.background());
+        }
+    }
+
+    /// Scales and pushes, returns the frame the result still has to be
+    /// centered on when it differs from the scaled size
+    fn process<P: KernelPixel>(&self, kernel: &mut ImageKernel<P>) -> Option<(u32, u32)> {
+        let ((width, height), (frame_width, frame_height)) =
+            self.resize.layout(kernel.width(), kernel.height());
+
+        if width <= kernel.width()
+            && height <= kernel.height()
+            && (width, height) != (kernel.width(), kernel.height())
+        {
+            // Push at the source resolution first, the reduction then works on
+            // sharp lines instead of aliasing the already small image
+            self.push(kernel);
+            kernel.downscale(width, height, self.downscale_filter);
+        } else {
+            let (reduced_width, reduced_height) =
+                (min(width, kernel.width()), min(height, kernel.height()));
+            if (reduced_width, reduced_height) != (kernel.width(), kernel.height()) {
+                // One side shrinks while the other grows. The shrinking side
+                // is reduced like a plain reduction first, so the stages only
+                // enlarge.
+                self.push(kernel);
+                kernel.downscale(reduced_width, reduced_height, self.downscale_filter);
+            }
+            let (source_width, source_height) = (kernel.width(), kernel.height());
+            let factor_x = width as f64 / source_width as f64;
+            let factor_y = height as f64 / source_height as f64;
+            let factor = factor_x.max(factor_y);
+            let stages = self.stages.factors(factor);
+
+            let mut cumulative = 1.0;
+            for (i, stage) in stages.iter().enumerate() {
+                cumulative *= stage;
+                let (stage_width, stage_height) = if i == stages.len() - 1 {
+                    (width, height)
+                } else {
+                    // Share of the whole upscale done so far, so that both
+                    // sides progress together even with different factors
+                    let progress = cumulative.ln() / factor.ln();
+                    (
+                        max(
+                            (source_width as f64 * factor_x.powf(progress)).round() as u32,
+                            1,
+                        ),
+                        max(
+                            (source_height as f64 * factor_y.powf(progress)).round() as u32,
+                            1,
+                        ),
+                    )
+                };
+                kernel.scale(stage_width, stage_height);
+                self.push(kernel);
+            }
+        }
+
+        if (frame_width, frame_height) != (width, height) {
+            Some((frame_width, frame_height))
+        } else {
+            None
+        }
+    }
+
+    fn push<P: KernelPixel>(&self, kernel: &mut ImageKernel<P>) {
+        for _ in 0..self.iteration {
+            kernel.compute_luminance();
+            kernel.push_color(
+                image_kernel::clamp((self.push_color_strength * 255.0) as u16, 0, 0xFFFF),
+                self.adaptive.as_ref(),
+                self.color_blend,
+            );
+            kernel.compute_gradient(&self.gradient_curve);
+            kernel.push_gradient(
+                image_kernel::clamp((self.push_gradient_strength * 255.0) as u16, 0, 0xFFFF),
+                self.adaptive.as_ref(),
+            );
+        }
+    }
+}
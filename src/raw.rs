@@ -0,0 +1,235 @@
+use crate::image_kernel;
+use crate::pipeline::parse_size;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// Channel order of a headerless pixel, 8 bits per channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RawFormat {
+    Rgba8,
+    Bgra8,
+    Rgb8,
+}
+
+impl FromStr for RawFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RawFormat, String> {
+        match s {
+            "rgba8" => Ok(RawFormat::Rgba8),
+            "bgra8" => Ok(RawFormat::Bgra8),
+            "rgb8" => Ok(RawFormat::Rgb8),
+            _ => Err(format!("Unknown raw format {}", s)),
+        }
+    }
+}
+
+impl RawFormat {
+    pub fn channels(self) -> usize {
+        match self {
+            RawFormat::Rgb8 => 3,
+            RawFormat::Rgba8 | RawFormat::Bgra8 => 4,
+        }
+    }
+}
+
+/// Parses a `FORMAT[:STRIDE]` pixel layout, the stride being in bytes
+pub fn parse_format(s: &str) -> Result<(RawFormat, Option<usize>), String> {
+    let mut parts = s.splitn(2, ':');
+    let format = parts.next().unwrap_or("").parse::<RawFormat>()?;
+    let stride = parts
+        .next()
+        .map(|stride| {
+            stride
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid stride {}", stride))
+        })
+        .transpose()?;
+    Ok((format, stride))
+}
+
+/// Size and memory layout of a headerless pixel buffer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RawLayout {
+    pub width: u32,
+    pub height: u32,
+    pub format: RawFormat,
+    /// Bytes from the start of one row to the next
+    pub stride: usize,
+}
+
+/// Parses `WIDTHxHEIGHT:FORMAT[:STRIDE]`, rows being packed without a stride
+impl FromStr for RawLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RawLayout, String> {
+        let mut parts = s.splitn(2, ':');
+        let (width, height) = parse_size(parts.next().unwrap_or(""))?;
+        let (format, stride) = parse_format(
+            parts
+                .next()
+                .ok_or_else(|| format!("Missing raw format in {}", s))?,
+        )?;
+        RawLayout::new(width, height, format, stride)
+    }
+}
+
+impl RawLayout {
+    /// Checks that `stride` holds a row of pixels, packing the rows without it.
+    /// The buffer can't be empty.
+    pub fn new(
+        width: u32,
+        height: u32,
+        format: RawFormat,
+        stride: Option<usize>,
+    ) -> Result<RawLayout, String> {
+        let row = width as usize * format.channels();
+        let stride = stride.unwrap_or(row);
+        if width == 0 || height == 0 {
+            return Err("A raw buffer needs at least one pixel".to_string());
+        }
+        if stride < row {
+            return Err(format!(
+                "A stride of {} bytes is shorter than a row of {} bytes",
+                stride, row
+            ));
+        }
+        Ok(RawLayout {
+            width,
+            height,
+            format,
+            stride,
+        })
+    }
+
+    /// Bytes the buffer takes, the last row needing no padding
+    pub fn buffer_len(&self) -> usize {
+        self.stride * (self.height as usize - 1) + self.width as usize * self.format.channels()
+    }
+
+    fn size_error(&self, len: usize) -> String {
+        format!(
+            "The raw buffer has {} bytes, {}x{} pixels need {}",
+            len,
+            self.width,
+            self.height,
+            self.buffer_len()
+        )
+    }
+
+    fn rows<'a>(&self, buffer: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        let row = self.width as usize * self.format.channels();
+        buffer
+            .chunks(self.stride)
+            .take(self.height as usize)
+            .map(move |chunk| &chunk[..row])
+    }
+
+    /// Copies the pixels out of `buffer`, RGB staying without alpha
+    pub fn decode(&self, buffer: &[u8]) -> Result<image::DynamicImage, String> {
+        if buffer.len() < self.buffer_len() {
+            return Err(self.size_error(buffer.len()));
+        }
+        let mut pixels = Vec::with_capacity(self.width as usize * self.height as usize * 4);
+        for row in self.rows(buffer) {
+            match self.format {
+                RawFormat::Bgra8 => {
+                    for pixel in row.chunks_exact(4) {
+                        pixels.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+                    }
+                }
+                RawFormat::Rgba8 | RawFormat::Rgb8 => pixels.extend_from_slice(row),
+            }
+        }
+        let image = match self.format {
+            RawFormat::Rgb8 => image::RgbImage::from_raw(self.width, self.height, pixels)
+                .map(image::DynamicImage::ImageRgb8),
+            RawFormat::Rgba8 | RawFormat::Bgra8 => {
+                image::RgbaImage::from_raw(self.width, self.height, pixels)
+                    .map(image::DynamicImage::ImageRgba8)
+            }
+        };
+        image.ok_or_else(|| "The raw buffer doesn't fit its size".to_string())
+    }
+
+    /// Writes `image` into `buffer`, which holds `buffer_len` bytes. The padding
+    /// between rows is left as it is.
+    pub fn encode(&self, image: &image::RgbaImage, buffer: &mut [u8]) {
+        let channels = self.format.channels();
+        let rows = buffer.chunks_mut(self.stride);
+        for (row, pixels) in rows.zip(image.rows()) {
+            for (value, pixel) in row.chunks_exact_mut(channels).zip(pixels) {
+                match self.format {
+                    RawFormat::Rgba8 => value.copy_from_slice(&pixel.0),
+                    RawFormat::Bgra8 => {
+                        value.copy_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]])
+                    }
+                    RawFormat::Rgb8 => value.copy_from_slice(&pixel.0[..3]),
+                }
+            }
+        }
+    }
+}
+
+fn format_hint() -> image::error::ImageFormatHint {
+    image::error::ImageFormatHint::Name("raw".to_string())
+}
+
+/// Reads a buffer laid out as `layout`, `-` being stdin. Files are memory
+/// mapped instead of copied in.
+pub fn read(filename: &str, layout: &RawLayout) -> image::ImageResult<image::DynamicImage> {
+    let image = if filename == "-" {
+        let mut buffer = Vec::with_capacity(layout.buffer_len());
+        std::io::stdin()
+            .take(layout.buffer_len() as u64)
+            .read_to_end(&mut buffer)?;
+        layout.decode(&buffer)
+    } else {
+        let file = std::fs::File::open(filename)?;
+        let len = file.metadata()?.len() as usize;
+        if len < layout.buffer_len() {
+            // An empty file can't be mapped
+            Err(layout.size_error(len))
+        } else {
+            // The map is only read and dropped before returning
+            let buffer = unsafe { memmap2::Mmap::map(&file)? };
+            layout.decode(&buffer)
+        }
+    };
+    image.map_err(|e| {
+        image::ImageError::Decoding(image::error::DecodingError::new(format_hint(), e))
+    })
+}
+
+/// Writes `image` as a headerless buffer in `format`, with rows `stride` bytes
+/// apart. Files are written through a memory map, `-` goes to stdout.
+pub fn write(
+    image: &image::RgbaImage,
+    filename: &str,
+    format: RawFormat,
+    stride: Option<usize>,
+) -> image::ImageResult<()> {
+    let layout = RawLayout::new(image.width(), image.height(), format, stride).map_err(|e| {
+        image::ImageError::Encoding(image::error::EncodingError::new(format_hint(), e))
+    })?;
+    if filename == "-" {
+        let mut buffer = vec![0; layout.buffer_len()];
+        layout.encode(image, &mut buffer);
+        let mut output = image_kernel::create(filename)?;
+        output.write_all(&buffer)?;
+        output.flush()?;
+    } else {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(filename)?;
+        file.set_len(layout.buffer_len() as u64)?;
+        // The file was just created at this size and nothing else maps it
+        let mut buffer = unsafe { memmap2::MmapMut::map_mut(&file)? };
+        layout.encode(image, &mut buffer);
+        buffer.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use crate::image_kernel::{self, ImageKernel};
+use crate::metadata::Metadata;
+use crate::pipeline::Pipeline;
+use std::path::Path;
+
+/// Puts `index` in place of the `%d` or `%0Nd` of `pattern`, `%%` standing
+/// for a percent sign
+pub fn frame_path(pattern: &str, index: u64) -> Result<String, String> {
+    let mut path = String::with_capacity(pattern.len() + 8);
+    let mut numbered = false;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        if chars.peek() == Some(&'%') {
+            chars.next();
+            path.push('%');
+            continue;
+        }
+        let mut width = String::new();
+        while let Some(&digit) = chars.peek().filter(|c| c.is_ascii_digit()) {
+            width.push(digit);
+            chars.next();
+        }
+        if chars.next() != Some('d') || numbered {
+            return Err(format!("The pattern {} needs a single %d or %0Nd", pattern));
+        }
+        let width = width.parse::<usize>().unwrap_or(0);
+        path.push_str(&format!("{:0width$}", index, width = width));
+        numbered = true;
+    }
+    if !numbered {
+        return Err(format!("The pattern {} needs a single %d or %0Nd", pattern));
+    }
+    Ok(path)
+}
+
+/// Whether `filename` is a written frame. Frames only get their name once
+/// the `.part-` file they are saved to is complete, so any that isn't empty is.
+pub fn is_done(filename: &str) -> bool {
+    std::fs::metadata(filename).is_ok_and(|metadata| metadata.is_file() && metadata.len() > 0)
+}
+
+/// A numbered run of frames
+pub struct Sequence {
+    /// Pattern of the input frames, such as `in/%06d.png`
+    pub input: String,
+    pub output: String,
+    pub input_format: Option<image::ImageFormat>,
+    pub output_format: image::ImageFormat,
+    /// First frame, the first one found from 0 to 4 when not given
+    pub start: Option<u64>,
+    /// Last frame, the one before the first missing input when not given
+    pub end: Option<u64>,
+    /// Leaves the metadata of the frames out
+    pub strip: bool,
+}
+
+/// Frames of a `Sequence::upscale` run
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Progress {
+    pub upscaled: u64,
+    /// Frames whose output was complete already
+    pub skipped: u64,
+}
+
+impl Sequence {
+    /// Runs `pipeline` on every frame with `kernel`, which carries the
+    /// settings from one frame to the next. Outputs that are complete already
+    /// are kept, and every frame is written to a hidden `.part-` file first
+    /// and renamed once done, so that a run cut short can simply be started again.
+    pub fn upscale(
+        &self,
+        kernel: &mut ImageKernel,
+        pipeline: &Pipeline,
+        target_profile: Option<&[u8]>,
+    ) -> Result<Progress, String> {
+        frame_path(&self.input, 0)?;
+        frame_path(&self.output, 0)?;
+        let start = match self.start {
+            Some(start) => start,
+            None => (0..=4)
+                .find(|&index| {
+                    frame_path(&self.input, index).is_ok_and(|path| Path::new(&path).exists())
+                })
+                .ok_or_else(|| format!("No frame of {} was found", self.input))?,
+        };
+        let end = self.end.unwrap_or(u64::MAX);
+
+        let mut progress = Progress::default();
+        for index in start..=end {
+            let input = frame_path(&self.input, index)?;
+            let output = frame_path(&self.output, index)?;
+            if self.end.is_none() && !Path::new(&input).exists() {
+                break;
+            }
+            if is_done(&output) {
+                progress.skipped += 1;
+                continue;
+            }
+
+            let source = image_kernel::read(&input, self.input_format)
+                .map_err(|e| format!("Can't upscale {}: {}", input, e))?;
+            let metadata = if self.strip {
+                Metadata::default()
+            } else {
+                source.metadata
+            };
+
+            let output_path = Path::new(&output);
+            if let Some(directory) = output_path.parent() {
+                std::fs::create_dir_all(directory).map_err(|e| e.to_string())?;
+            }
+            // Hidden and with the same extension, which tells PAM from PPM
+            let partial = output_path.with_file_name(format!(
+                ".part-{}",
+                output_path
+                    .file_name()
+                    .map_or(String::new(), |name| name.to_string_lossy().into_owned())
+            ));
+            let saved = if target_profile.is_none() && pipeline.runs_luma(source.image.color()) {
+                // Grayscale frames stay a single plane, like grayscale images
+                let mut luma = ImageKernel::from_luma_image(source.image);
+                luma.icc_profile = source.icc_profile;
+                luma.metadata = metadata;
+                luma.quantization = kernel.quantization;
+                luma.encoding = kernel.encoding;
+                pipeline.run_luma(&mut luma);
+                luma.save(&partial.to_string_lossy(), Some(self.output_format))
+            } else {
+                kernel.load(source.image);
+                kernel.icc_profile = source.icc_profile;
+                kernel.metadata = metadata;
+                pipeline.run(kernel);
+                if let Some(target_profile) = target_profile {
+                    kernel.convert_profile(target_profile)?;
+                }
+                kernel.save(&partial.to_string_lossy(), Some(self.output_format))
+            };
+            saved.map_err(|e| format!("Can't save {}: {}", output, e))?;
+            std::fs::rename(&partial, &output).map_err(|e| e.to_string())?;
+            progress.upscaled += 1;
+        }
+        Ok(progress)
+    }
+}
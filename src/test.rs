@@ -1,5 +1,6 @@
 use super::*;
 
+/// Upscales `input_filename` into `output_filename` in the temporary directory
 fn anime4k(
     input_filename: &str,
     output_filename: &str,
@@ -11,55 +12,1580 @@ fn anime4k(
     let image = image::open(input_filename).expect("Can't open image.");
 
     let mut kernel_instance = image_kernel::ImageKernel::from_image(image);
-    kernel_instance.scale(
-        (kernel_instance.width() as f64 * scale) as u32,
-        (kernel_instance.height() as f64 * scale) as u32,
-    );
-    for _ in 0..iteration {
-        kernel_instance.compute_luminance();
-        kernel_instance.push_color(image_kernel::clamp(
-            (push_color_strength * 255.0) as u16,
-            0,
-            0xFFFF,
-        ));
-        kernel_instance.compute_gradient();
-        kernel_instance.push_gradient(image_kernel::clamp(
-            (push_gradient_strength * 255.0) as u16,
-            0,
-            0xFFFF,
-        ));
-    }
+    let pipeline = pipeline::Pipeline {
+        resize: pipeline::Resize::Factor { x: scale, y: scale },
+        iteration,
+        push_color_strength,
+        push_gradient_strength,
+        ..pipeline::Pipeline::default()
+    };
+    pipeline.run(&mut kernel_instance);
+    let output = std::env::temp_dir().join(output_filename);
     kernel_instance
-        .save(output_filename)
+        .save(output.to_str().unwrap(), None)
         .expect("Can't save image.");
 }
 
 #[test]
 fn test_small_eye_image_default() {
-    anime4k("assets/eye-in.png", "assets/eye-out.png", 2.0, 1, 0.0, 1.0);
+    anime4k("assets/eye-in.png", "anime4k-eye-out.png", 2.0, 1, 0.0, 1.0);
 }
 
 #[test]
 fn test_small_eye_image_more_color_strength() {
-    anime4k("assets/eye-in.png", "assets/eye-more-color-strength-out.png", 2.0, 1, 1.0, 1.0);
+    anime4k("assets/eye-in.png", "anime4k-eye-more-color-strength-out.png", 2.0, 1, 1.0, 1.0);
 }
 
 #[test]
 fn test_small_eye_image_more_iteration() {
-    anime4k("assets/eye-in.png", "assets/eye-more-iteration-out.png", 2.0, 2, 0.0, 1.0);
+    anime4k("assets/eye-in.png", "anime4k-eye-more-iteration-out.png", 2.0, 2, 0.0, 1.0);
 }
 
 #[test]
 fn test_small_eye_image_more_scale() {
-    anime4k("assets/eye-in.png", "assets/eye-more-scale-out.png", 4.0, 1, 0.0, 1.0);
+    anime4k("assets/eye-in.png", "anime4k-eye-more-scale-out.png", 4.0, 1, 0.0, 1.0);
 }
 
 #[test]
 fn test_people_default() {
-    anime4k("assets/people-in.png", "assets/people-out.png", 2.0, 1, 0.0, 1.0);
+    anime4k("assets/people-in.png", "anime4k-people-out.png", 2.0, 1, 0.0, 1.0);
 }
 
 #[test]
 fn test_scenery_image_default() {
-    anime4k("assets/scenery-in.png", "assets/scenery-out.png", 2.0, 1, 0.0, 1.0);
+    anime4k("assets/scenery-in.png", "anime4k-scenery-out.png", 2.0, 1, 0.0, 1.0);
+}
+
+#[test]
+fn test_gradient_curve_default_is_hard_clamp() {
+    let curve = image_kernel::GradientCurve::default();
+    assert_eq!(curve.response(0.0), 0.0);
+    assert_eq!(curve.response(100.0), 100.0);
+    assert_eq!(curve.response(255.0), 255.0);
+    assert_eq!(curve.response(1000.0), 255.0);
+}
+
+#[test]
+fn test_gradient_curve_threshold_and_knee() {
+    let curve = image_kernel::GradientCurve {
+        gain: 2.0,
+        threshold: 40.0,
+        gamma: 1.0,
+        knee: 0.25,
+    };
+    // 15 * 2 is under the threshold
+    assert_eq!(curve.response(15.0), 0.0);
+    assert_eq!(curve.response(25.0), 50.0);
+    // Inside the knee the response is compressed but still increasing
+    let a = curve.response(115.0);
+    let b = curve.response(135.0);
+    assert!(a < 230.0 && a < b && b < 255.0);
+    assert_eq!(curve.response(200.0), 255.0);
+}
+
+#[test]
+fn test_adaptive_strength_follows_contrast() {
+    // Left half is flat, right half is a fine checkerboard
+    let image = image::ImageBuffer::from_fn(32, 16, |x, y| {
+        if x >= 16 && (x + y) % 2 == 0 {
+            image::Rgba([255u8, 255, 255, 255])
+        } else {
+            image::Rgba([0u8, 0, 0, 255])
+        }
+    });
+    let kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba8(image));
+    let adaptive = image_kernel::AdaptiveStrength {
+        radius: 2,
+        min: 0.25,
+        max: 1.0,
+    };
+    let map = kernel.strength_map(200, &adaptive);
+
+    assert_eq!(map[8 * 32 + 4], 50);
+    assert!(map[8 * 32 + 24] > 180);
+}
+
+#[test]
+fn test_area_downscale_averages_covered_pixels() {
+    let image = image::ImageBuffer::from_fn(4, 4, |x, y| {
+        if (x + y) % 2 == 0 {
+            image::Rgba([200u8, 100, 0, 255])
+        } else {
+            image::Rgba([0u8, 100, 200, 255])
+        }
+    });
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba8(image));
+    kernel.downscale(2, 2, image_kernel::DownscaleFilter::Area);
+
+    assert_eq!(kernel.width(), 2);
+    for pixel in kernel.to_rgba8().pixels() {
+        assert_eq!(*pixel, image::Rgba([100u8, 100, 100, 255]));
+    }
+}
+
+#[test]
+fn test_small_eye_image_downscale() {
+    anime4k("assets/eye-in.png", "anime4k-eye-downscale-out.png", 0.5, 1, 0.0, 1.0);
+    let output = std::env::temp_dir().join("anime4k-eye-downscale-out.png");
+    let (width, height) = image::image_dimensions("assets/eye-in.png").unwrap();
+    assert_eq!(
+        image::image_dimensions(&output).unwrap(),
+        (
+            (width as f64 * 0.5).round() as u32,
+            (height as f64 * 0.5).round() as u32
+        )
+    );
+}
+
+#[test]
+fn test_resize_layout() {
+    let target = |width, height, mode, padding| pipeline::Resize::Target {
+        width,
+        height,
+        mode,
+        padding,
+    };
+
+    assert_eq!(
+        pipeline::Resize::Factor { x: 1.5, y: 3.0 }.layout(101, 10),
+        ((152, 30), (152, 30))
+    );
+    assert_eq!(
+        target(Some(200), None, pipeline::FitMode::Fit, None).layout(100, 50),
+        ((200, 100), (200, 100))
+    );
+    assert_eq!(
+        target(Some(1920), Some(1080), pipeline::FitMode::Fit, None).layout(400, 300),
+        ((1440, 1080), (1440, 1080))
+    );
+    assert_eq!(
+        target(
+            Some(1920),
+            Some(1080),
+            pipeline::FitMode::Fit,
+            Some(image::Rgba([0, 0, 0, 255]))
+        )
+        .layout(400, 300),
+        ((1440, 1080), (1920, 1080))
+    );
+    assert_eq!(
+        target(Some(1920), Some(1080), pipeline::FitMode::Fill, None).layout(400, 300),
+        ((1920, 1440), (1920, 1080))
+    );
+    assert_eq!(
+        target(Some(1920), Some(1080), pipeline::FitMode::Stretch, None).layout(400, 300),
+        ((1920, 1080), (1920, 1080))
+    );
+}
+
+#[test]
+fn test_parse_size_and_color() {
+    assert_eq!(pipeline::parse_size("1080p"), Ok((1920, 1080)));
+    assert_eq!(pipeline::parse_size("4K"), Ok((3840, 2160)));
+    assert_eq!(pipeline::parse_size("640x360"), Ok((640, 360)));
+    assert!(pipeline::parse_size("640x").is_err());
+    assert!(pipeline::parse_size("0x360").is_err());
+    assert_eq!(pipeline::parse_side("640"), Ok(640));
+    assert!(pipeline::parse_side("0").is_err());
+    assert!(pipeline::parse_side("-1").is_err());
+    assert_eq!(
+        pipeline::parse_color("#ff8000"),
+        Ok(image::Rgba([255, 128, 0, 255]))
+    );
+    assert_eq!(
+        pipeline::parse_color("10203040"),
+        Ok(image::Rgba([16, 32, 48, 64]))
+    );
+    assert!(pipeline::parse_color("#fff").is_err());
+}
+
+#[test]
+fn test_small_eye_image_padded_frame() {
+    let image = image::open("assets/eye-in.png").expect("Can't open image.");
+    let mut kernel = image_kernel::ImageKernel::from_image(image);
+    let pipeline = pipeline::Pipeline {
+        resize: pipeline::Resize::Target {
+            width: Some(kernel.width() * 3),
+            height: Some(kernel.height() * 2),
+            mode: pipeline::FitMode::Fit,
+            padding: Some(image::Rgba([255, 0, 255, 255])),
+        },
+        ..pipeline::Pipeline::default()
+    };
+    let (width, height) = (kernel.width(), kernel.height());
+    pipeline.run(&mut kernel);
+
+    assert_eq!((kernel.width(), kernel.height()), (width * 3, height * 2));
+    assert_eq!(
+        *kernel.to_rgba8().get_pixel(0, 0),
+        image::Rgba([255, 0, 255, 255])
+    );
+}
+
+#[test]
+fn test_progressive_stages() {
+    assert_eq!(pipeline::Stages::Single.factors(4.0), vec![4.0]);
+    assert_eq!(pipeline::Stages::Step(2.0).factors(4.0), vec![2.0, 2.0]);
+    assert_eq!(
+        pipeline::Stages::Step(2.0).factors(6.0),
+        vec![2.0, 2.0, 1.5]
+    );
+    assert_eq!(pipeline::Stages::Step(2.0).factors(1.5), vec![1.5]);
+    assert_eq!(
+        pipeline::Stages::Schedule(vec![3.0, 2.0, 2.0]).factors(9.0),
+        vec![3.0, 2.0, 1.5]
+    );
+
+    assert_eq!(pipeline::parse_stages("2, 1.5"), Ok(vec![2.0, 1.5]));
+    for stages in &["2,0", "2,-1", "NaN", "inf", "2,"] {
+        assert!(pipeline::parse_stages(stages).is_err());
+    }
+}
+
+#[test]
+fn test_small_eye_image_progressive_scale() {
+    let image = image::open("assets/eye-in.png").expect("Can't open image.");
+    let mut kernel = image_kernel::ImageKernel::from_image(image);
+    let (width, height) = (kernel.width(), kernel.height());
+    let pipeline = pipeline::Pipeline {
+        resize: pipeline::Resize::Factor { x: 3.0, y: 3.0 },
+        stages: pipeline::Stages::Step(2.0),
+        ..pipeline::Pipeline::default()
+    };
+    pipeline.run(&mut kernel);
+
+    assert_eq!((kernel.width(), kernel.height()), (width * 3, height * 3));
+}
+
+#[test]
+fn test_parse_factor() {
+    assert_eq!(pipeline::parse_factor("0.5"), Ok(0.5));
+    assert_eq!(pipeline::parse_factor("3"), Ok(3.0));
+    for factor in &["0", "-2", "NaN", "inf", "-inf", "x"] {
+        assert!(pipeline::parse_factor(factor).is_err());
+    }
+}
+
+#[test]
+fn test_luma_models() {
+    let green = |model: image_kernel::LumaModel| model.luma(0.0, 1.0, 0.0);
+    assert_eq!(green(image_kernel::LumaModel::Approximate), 0.5);
+    assert_eq!(green(image_kernel::LumaModel::Bt601), 0.587);
+    assert_eq!(green(image_kernel::LumaModel::Bt709), 0.7152);
+    assert_eq!(green(image_kernel::LumaModel::Bt2020), 0.678);
+
+    for model in &[
+        image_kernel::LumaModel::Bt601,
+        image_kernel::LumaModel::Bt709,
+        image_kernel::LumaModel::Bt2020,
+    ] {
+        assert!((model.luma(1.0, 1.0, 1.0) - 1.0).abs() < 1e-6);
+    }
+
+    let image = image::ImageBuffer::from_pixel(2, 2, image::Rgba([0u8, 255, 0, 255]));
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba8(image));
+    kernel.luma_model = image_kernel::LumaModel::Bt709;
+    kernel.compute_luminance();
+    assert_eq!(kernel.image.get_pixel(1, 1)[3], 0.7152);
+}
+
+#[test]
+fn test_srgb_transfer_round_trip() {
+    for value in 0..=255u8 {
+        let encoded = value as f32 / 255.0;
+        let decoded = image_kernel::linear_to_srgb(image_kernel::srgb_to_linear(encoded));
+        assert_eq!((decoded * 255.0).round() as u8, value);
+    }
+    assert!((image_kernel::srgb_to_linear(0.5) - 0.214).abs() < 1e-3);
+}
+
+#[test]
+fn test_linear_downscale_of_stripes() {
+    // One pixel black and white stripes, averaged two by two
+    let stripes = image::ImageBuffer::from_fn(8, 8, |x, _| {
+        if x % 2 == 0 {
+            image::Rgba([0u8, 0, 0, 255])
+        } else {
+            image::Rgba([255u8, 255, 255, 255])
+        }
+    });
+
+    let mut kernel =
+        image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba8(stripes.clone()));
+    kernel.downscale(4, 4, image_kernel::DownscaleFilter::Area);
+    // 127.5, truncated
+    assert_eq!(kernel.to_rgba8().get_pixel(1, 1)[0], 127);
+
+    // Half of the light is emitted, which is 187.5 once encoded
+    let mut kernel =
+        image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba8(stripes));
+    kernel.decode_srgb();
+    kernel.downscale(4, 4, image_kernel::DownscaleFilter::Area);
+    kernel.encode_srgb();
+    assert_eq!(kernel.to_rgba8().get_pixel(1, 1)[0], 187);
+}
+
+#[test]
+fn test_mixed_resize_reduces_the_shrinking_side() {
+    // Horizontal stripes, halved vertically while doubled horizontally
+    let stripes = image::ImageBuffer::from_fn(8, 8, |_, y| {
+        if y % 2 == 0 {
+            image::Rgba([0u8, 0, 0, 255])
+        } else {
+            image::Rgba([255u8, 255, 255, 255])
+        }
+    });
+    let pipeline = pipeline::Pipeline {
+        resize: pipeline::Resize::Factor { x: 2.0, y: 0.5 },
+        iteration: 0,
+        downscale_filter: image_kernel::DownscaleFilter::Area,
+        ..pipeline::Pipeline::default()
+    };
+    let mut kernel =
+        image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba8(stripes));
+    pipeline.run(&mut kernel);
+
+    assert_eq!((kernel.width(), kernel.height()), (16, 4));
+    // Every pair of rows is averaged instead of sampled
+    for pixel in kernel.to_rgba8().pixels() {
+        assert!((pixel[0] as i32 - 128).abs() <= 1);
+    }
+}
+
+#[test]
+fn test_linear_ramp_stays_monotonic() {
+    let ramp = image::ImageBuffer::from_fn(64, 8, |x, _| {
+        let value = (x * 4) as u8;
+        image::Rgba([value, value, value, 255])
+    });
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba8(ramp));
+    let pipeline = pipeline::Pipeline {
+        linear: true,
+        ..pipeline::Pipeline::default()
+    };
+    pipeline.run(&mut kernel);
+
+    let output = kernel.to_rgba8();
+    for x in 2..output.width() - 3 {
+        assert!(output.get_pixel(x, 8)[0] <= output.get_pixel(x + 1, 8)[0]);
+    }
+}
+
+#[test]
+fn test_ycbcr_round_trip() {
+    let models = [
+        image_kernel::LumaModel::Approximate,
+        image_kernel::LumaModel::Bt601,
+        image_kernel::LumaModel::Bt709,
+        image_kernel::LumaModel::Bt2020,
+    ];
+    for &model in &models {
+        for &(r, g, b) in &[(1.0, 0.0, 0.0), (0.2, 0.9, 0.4), (0.0, 0.0, 1.0)] {
+            let (y, cb, cr) = image_kernel::rgb_to_ycbcr(model, r, g, b);
+            let (r2, g2, b2) = image_kernel::ycbcr_to_rgb(model, y, cb, cr);
+            assert!((r - r2).abs() < 1e-5 && (g - g2).abs() < 1e-5 && (b - b2).abs() < 1e-5);
+        }
+        let (y, cb, cr) = image_kernel::rgb_to_ycbcr(model, 0.5, 0.5, 0.5);
+        assert!((y - 0.5).abs() < 1e-6 && (cb - 0.5).abs() < 1e-6 && (cr - 0.5).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn test_ycbcr_push_leaves_chroma_alone() {
+    // Saturated red line on blue, the push passes may only move luma
+    let image = image::ImageBuffer::from_fn(16, 16, |x, _| {
+        if x == 7 || x == 8 {
+            image::Rgba([220u8, 20, 30, 255])
+        } else {
+            image::Rgba([30u8, 40, 200, 255])
+        }
+    });
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba8(image));
+    let source = kernel.to_ycbcr();
+    let pipeline = pipeline::Pipeline {
+        resize: pipeline::Resize::Factor { x: 1.0, y: 1.0 },
+        push_color_strength: 1.0,
+        ycbcr: true,
+        ..pipeline::Pipeline::default()
+    };
+    let planes = pipeline.run_ycbcr(&mut kernel);
+
+    assert_eq!(&*planes.cb, &*source.cb);
+    assert_eq!(&*planes.cr, &*source.cr);
+    assert!(planes
+        .luma
+        .image
+        .pixels()
+        .zip(source.luma.image.pixels())
+        .any(|(pushed, original)| pushed[0] != original[0]));
+
+    let output = std::env::temp_dir().join("anime4k-ycbcr-out.yuv");
+    planes.save_yuv(output.to_str().unwrap()).unwrap();
+    assert_eq!(std::fs::metadata(&output).unwrap().len(), 16 * 16 * 3);
+}
+
+#[test]
+fn test_small_eye_image_ycbcr() {
+    let image = image::open("assets/eye-in.png").expect("Can't open image.");
+    let mut kernel = image_kernel::ImageKernel::from_image(image);
+    let (width, height) = (kernel.width(), kernel.height());
+    let pipeline = pipeline::Pipeline {
+        ycbcr: true,
+        chroma_filter: image_kernel::ChromaFilter::Lanczos,
+        ..pipeline::Pipeline::default()
+    };
+    pipeline.run(&mut kernel);
+
+    assert_eq!((kernel.width(), kernel.height()), (width * 2, height * 2));
+}
+
+#[test]
+fn test_sixteen_bit_round_trip() {
+    // Every value is between two 8 bit steps
+    let ramp = image::ImageBuffer::from_fn(256, 4, |x, y| {
+        let value = (x * 256 + y * 64 + 100) as u16;
+        image::Rgba([value, 65535 - value, value / 2, 65535])
+    });
+    let kernel =
+        image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba16(ramp.clone()));
+    assert_eq!(kernel.bit_depth, image_kernel::BitDepth::Sixteen);
+
+    let output = std::env::temp_dir().join("anime4k-sixteen-bit-out.png");
+    kernel.save(output.to_str().unwrap(), None).unwrap();
+    let saved = image::open(&output).unwrap();
+    assert_eq!(saved.color(), image::ColorType::Rgba16);
+    assert_eq!(saved.to_rgba16().into_raw(), ramp.into_raw());
+}
+
+#[test]
+fn test_sixteen_bit_pipeline_keeps_tonal_resolution() {
+    let ramp = image::ImageBuffer::from_fn(64, 16, |x, _| {
+        let value = (x * 1024 / 64 + 20000) as u16;
+        image::Rgb([value, value, value])
+    });
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgb16(ramp));
+    pipeline::Pipeline::default().run(&mut kernel);
+
+    // The ramp only spans four 8 bit steps
+    let mut values: Vec<u16> = kernel.to_rgba16().pixels().map(|pixel| pixel[0]).collect();
+    values.sort_unstable();
+    values.dedup();
+    assert!(values.len() > 64);
+}
+
+#[test]
+fn test_openexr_round_trip() {
+    let frame = image::ImageBuffer::from_fn(16, 8, |x, y| {
+        let value = x as f32 * 0.75 + y as f32 * 0.125;
+        image::Rgba([value, value * 2.0, 0.25, 1.0])
+    });
+    let kernel =
+        image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba32F(frame.clone()));
+    assert_eq!(kernel.bit_depth, image_kernel::BitDepth::Float);
+
+    let output = std::env::temp_dir().join("anime4k-hdr-out.exr");
+    kernel.save(output.to_str().unwrap(), None).unwrap();
+    let saved = image::open(&output).unwrap();
+    assert_eq!(saved.into_rgba32f().into_raw(), frame.into_raw());
+}
+
+#[test]
+fn test_openexr_to_png_is_srgb_encoded() {
+    // A mid grey in linear light, and a highlight above 1 that is clipped to white
+    let grey = image_kernel::srgb_to_linear(128.0 / 255.0);
+    let frame = image::ImageBuffer::from_fn(4, 2, |_, y| {
+        let value = if y == 0 { grey } else { 4.0 };
+        image::Rgba([value, value, value, 1.0])
+    });
+    let input = std::env::temp_dir().join("anime4k-hdr-grey-in.exr");
+    image::DynamicImage::ImageRgba32F(frame)
+        .save(&input)
+        .unwrap();
+
+    let source = image_kernel::read(input.to_str().unwrap(), None).unwrap();
+    let kernel = image_kernel::ImageKernel::from_image(source.image);
+    assert!(kernel.linear);
+    let output = std::env::temp_dir().join("anime4k-hdr-grey-out.png");
+    kernel.save(output.to_str().unwrap(), None).unwrap();
+
+    let saved = image::open(&output).unwrap().to_rgba8();
+    assert_eq!(*saved.get_pixel(0, 0), image::Rgba([128, 128, 128, 255]));
+    assert_eq!(*saved.get_pixel(0, 1), image::Rgba([255, 255, 255, 255]));
+}
+
+#[test]
+fn test_png_to_openexr_and_back_keeps_values() {
+    let exr = std::env::temp_dir().join("anime4k-grey-round-trip.exr");
+    let png = std::env::temp_dir().join("anime4k-grey-round-trip.png");
+    let round_trip = |image: image::DynamicImage| {
+        if image.color().has_color() {
+            image_kernel::ImageKernel::from_image(image)
+                .save(exr.to_str().unwrap(), None)
+                .unwrap();
+        } else {
+            image_kernel::ImageKernel::from_luma_image(image)
+                .save(exr.to_str().unwrap(), None)
+                .unwrap();
+        }
+        let source = image_kernel::read(exr.to_str().unwrap(), None).unwrap();
+        image_kernel::ImageKernel::from_image(source.image)
+            .save(png.to_str().unwrap(), None)
+            .unwrap();
+        image::open(&png).unwrap().to_rgba8()
+    };
+
+    let color = image::ImageBuffer::from_pixel(4, 4, image::Rgba([128u8, 64, 200, 255]));
+    let saved = round_trip(image::DynamicImage::ImageRgba8(color));
+    assert_eq!(*saved.get_pixel(1, 1), image::Rgba([128, 64, 200, 255]));
+
+    let grey = image::ImageBuffer::from_pixel(4, 4, image::Luma([128u8]));
+    let saved = round_trip(image::DynamicImage::ImageLuma8(grey));
+    assert_eq!(*saved.get_pixel(1, 1), image::Rgba([128, 128, 128, 255]));
+}
+
+#[test]
+fn test_hdr_pipeline_does_not_clip() {
+    // A highlight eight times brighter than diffuse white on a dim background
+    let frame = image::ImageBuffer::from_fn(16, 16, |x, y| {
+        let value = if (4..12).contains(&x) && (4..12).contains(&y) {
+            8.0
+        } else {
+            0.1
+        };
+        image::Rgb([value, value, value])
+    });
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgb32F(frame));
+    pipeline::Pipeline::default().run(&mut kernel);
+
+    let peak = kernel
+        .image
+        .pixels()
+        .map(|pixel| pixel[0])
+        .fold(0.0, f32::max);
+    assert!(peak > 7.5);
+}
+
+#[test]
+fn test_hdr_gradient_is_relative_to_luminance() {
+    let edge = |low: f32, high: f32| {
+        let frame = image::ImageBuffer::from_fn(8, 8, |x, _| {
+            let value = if x < 4 { low } else { high };
+            image::Rgb([value, value, value])
+        });
+        let mut kernel =
+            image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgb32F(frame));
+        kernel.compute_luminance();
+        kernel.compute_gradient(&image_kernel::GradientCurve::default());
+        kernel.image.get_pixel(4, 4)[3]
+    };
+
+    // The same edge one stop brighter gets the same response
+    assert!((edge(2.0, 2.1) - edge(4.0, 4.2)).abs() < 1e-6);
+    assert!(edge(2.0, 2.1) > 0.0 && edge(2.0, 2.1) < 1.0);
+}
+
+#[test]
+fn test_icc_profile_is_carried_to_output() {
+    let profile = icc::load("display-p3").unwrap();
+    let mut encoded = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::ImageRgb8(image::ImageBuffer::from_pixel(
+        8,
+        8,
+        image::Rgb([200, 40, 40]),
+    ))
+    .write_to(&mut encoded, image::ImageFormat::Png)
+    .unwrap();
+    let input = std::env::temp_dir().join("anime4k-icc-in.png");
+    std::fs::write(&input, icc::embed(encoded.into_inner(), &profile)).unwrap();
+
+    let source = image_kernel::read(input.to_str().unwrap(), None).unwrap();
+    assert_eq!(source.icc_profile.as_ref(), Some(&profile));
+    let mut kernel = image_kernel::ImageKernel::from_image(source.image);
+    kernel.icc_profile = source.icc_profile;
+    pipeline::Pipeline::default().run(&mut kernel);
+
+    let output = std::env::temp_dir().join("anime4k-icc-out.png");
+    kernel.save(output.to_str().unwrap(), None).unwrap();
+    let saved = std::fs::read(&output).unwrap();
+    assert_eq!(icc::extract(&saved), Some(profile));
+}
+
+#[test]
+fn test_icc_profile_conversion() {
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgb8(
+        image::ImageBuffer::from_pixel(2, 2, image::Rgb([160, 100, 80])),
+    ));
+    let source = *kernel.image.get_pixel(0, 0);
+
+    // The same color is less saturated within the wider Display P3 gamut
+    kernel
+        .convert_profile(&icc::load("display-p3").unwrap())
+        .unwrap();
+    let p3 = *kernel.image.get_pixel(0, 0);
+    assert!(p3[0] < source[0] && p3[2] > source[2]);
+
+    let srgb = icc::load("srgb").unwrap();
+    kernel.convert_profile(&srgb).unwrap();
+    let pixel = kernel.image.get_pixel(0, 0);
+    for channel in 0..3 {
+        assert!((pixel[channel] - source[channel]).abs() < 1e-2);
+    }
+    assert_eq!(kernel.icc_profile, Some(srgb));
+}
+
+#[test]
+fn test_icc_profile_conversion_of_linear_light() {
+    let color = [160u8, 100, 80];
+    let mut encoded = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgb8(
+        image::ImageBuffer::from_pixel(2, 2, image::Rgb(color)),
+    ));
+    let linear = color.map(|value| image_kernel::srgb_to_linear(value as f32 / 255.0));
+    let mut float = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgb32F(
+        image::ImageBuffer::from_pixel(2, 2, image::Rgb(linear)),
+    ));
+    assert!(float.linear);
+
+    let p3 = icc::load("display-p3").unwrap();
+    encoded.convert_profile(&p3).unwrap();
+    float.convert_profile(&p3).unwrap();
+    assert!(float.linear);
+    let (encoded, float) = (encoded.to_rgba8(), float.to_rgba8());
+    for channel in 0..3 {
+        let difference =
+            encoded.get_pixel(0, 0)[channel] as i32 - float.get_pixel(0, 0)[channel] as i32;
+        assert!(difference.abs() <= 1);
+    }
+}
+
+#[test]
+fn test_color_blend_keeps_hue_on_saturated_edge() {
+    let blue = image::Rgb([40u8, 50, 230]);
+    let edge = image::ImageBuffer::from_fn(8, 8, |x, _| {
+        if x < 4 {
+            blue
+        } else {
+            image::Rgb([255, 255, 255])
+        }
+    });
+    let hue = |r: f32, g: f32, b: f32| {
+        let (_, a, b) = image_kernel::linear_to_oklab(
+            image_kernel::srgb_to_linear(r),
+            image_kernel::srgb_to_linear(g),
+            image_kernel::srgb_to_linear(b),
+        );
+        b.atan2(a).to_degrees()
+    };
+    let blue_hue = hue(
+        blue[0] as f32 / 255.0,
+        blue[1] as f32 / 255.0,
+        blue[2] as f32 / 255.0,
+    );
+
+    // Largest hue change over the blue side of the edge
+    let hue_change = |blend: image_kernel::ColorBlend| {
+        let mut kernel =
+            image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgb8(edge.clone()));
+        kernel.compute_luminance();
+        kernel.push_color(0x80, None, blend);
+        assert!(kernel.image.get_pixel(3, 4)[0] > blue[0] as f32 / 255.0 + 0.1);
+        (0..4)
+            .map(|x| {
+                let pixel = kernel.image.get_pixel(x, 4);
+                (hue(pixel[0], pixel[1], pixel[2]) - blue_hue).abs()
+            })
+            .fold(0.0, f32::max)
+    };
+
+    let rgb = hue_change(image_kernel::ColorBlend::Rgb);
+    let oklab = hue_change(image_kernel::ColorBlend::Oklab);
+    let luma = hue_change(image_kernel::ColorBlend::Luma);
+    assert!(oklab < 0.5);
+    assert!(rgb > 5.0);
+    assert!(luma < rgb);
+}
+
+#[test]
+fn test_grayscale_stays_single_channel() {
+    let page = image::open("assets/eye-in.png").unwrap().to_luma8();
+    let mut kernel =
+        image_kernel::ImageKernel::from_luma_image(image::DynamicImage::ImageLuma8(page.clone()));
+    pipeline::Pipeline::default().run_luma(&mut kernel);
+
+    // Gray RGBA takes the same decisions, its luma being the gray value up to
+    // float rounding
+    let mut rgba =
+        image_kernel::ImageKernel::from_image(image::DynamicImage::ImageLuma8(page.clone()));
+    pipeline::Pipeline::default().run(&mut rgba);
+    for (gray, color) in kernel.image.pixels().zip(rgba.image.pixels()) {
+        assert!((gray[0] - color[0]).abs() <= 1.0 / 255.0);
+    }
+
+    let output = std::env::temp_dir().join("anime4k-grayscale-out.png");
+    kernel.save(output.to_str().unwrap(), None).unwrap();
+    let saved = image::open(&output).unwrap();
+    assert_eq!(saved.color(), image::ColorType::L8);
+    assert_eq!(
+        (saved.width(), saved.height()),
+        (page.width() * 2, page.height() * 2)
+    );
+}
+
+#[test]
+fn test_indexed_png_keeps_source_palette() {
+    let source_palette = palette::Palette {
+        colors: vec![
+            [0, 0, 0, 0xFF],
+            [255, 255, 255, 0xFF],
+            [200, 30, 30, 0xFF],
+            [30, 30, 200, 0xFF],
+        ],
+    };
+    let indices: Vec<u8> = (0..16 * 16).map(|i| ((i % 16) / 4) as u8).collect();
+    let input = std::env::temp_dir().join("anime4k-indexed-in.png");
+    std::fs::write(&input, source_palette.encode_png(16, 16, &indices).unwrap()).unwrap();
+
+    let source = image_kernel::read(input.to_str().unwrap(), None).unwrap();
+    assert_eq!(source.palette.as_ref(), Some(&source_palette));
+    let mut kernel = image_kernel::ImageKernel::from_image(source.image);
+    pipeline::Pipeline::default().run(&mut kernel);
+
+    let output = std::env::temp_dir().join("anime4k-indexed-out.png");
+    kernel
+        .save_indexed(
+            output.to_str().unwrap(),
+            &source_palette,
+            palette::Dither::ErrorDiffusion,
+        )
+        .unwrap();
+    let saved = std::fs::read(&output).unwrap();
+    assert_eq!(palette::Palette::read(&saved), Some(source_palette.clone()));
+    let saved = image::load_from_memory(&saved).unwrap().to_rgba8();
+    assert_eq!(saved.dimensions(), (32, 32));
+    assert!(saved
+        .pixels()
+        .all(|pixel| source_palette.colors.contains(&pixel.0)));
+}
+
+#[test]
+fn test_dither_keeps_average_tone() {
+    let black_and_white = palette::Palette {
+        colors: vec![[0, 0, 0, 0xFF], [255, 255, 255, 0xFF]],
+    };
+    let gray = image::ImageBuffer::from_pixel(32, 32, image::Rgba([64u8, 64, 64, 0xFF]));
+    let white_share = |dither: palette::Dither| {
+        let indices = black_and_white.quantize(&gray, dither);
+        indices.iter().filter(|&&i| i == 1).count() as f32 / indices.len() as f32
+    };
+
+    assert_eq!(white_share(palette::Dither::None), 0.0);
+    assert!((white_share(palette::Dither::Ordered) - 0.25).abs() < 0.05);
+    assert!((white_share(palette::Dither::ErrorDiffusion) - 0.25).abs() < 0.05);
+}
+
+#[test]
+fn test_palette_color_count() {
+    assert_eq!(palette::parse_colors("2"), Ok(2));
+    assert_eq!(palette::parse_colors("256"), Ok(256));
+    assert!(palette::parse_colors("1").is_err());
+    assert!(palette::parse_colors("257").is_err());
+    assert!(palette::parse_colors("many").is_err());
+}
+
+#[test]
+fn test_generated_palette_is_exact_for_few_colors() {
+    let colors = [[255, 0, 0, 0xFF], [0, 255, 0, 0xFF], [0, 0, 255, 0x80]];
+    let image = image::ImageBuffer::from_fn(30, 30, |x, _| image::Rgba(colors[x as usize % 3]));
+
+    assert_eq!(
+        palette::Palette::generate(&image, 3).colors,
+        colors.to_vec()
+    );
+    assert_eq!(palette::Palette::generate(&image, 2).colors.len(), 2);
+}
+
+#[test]
+fn test_quantization_modes() {
+    // Every value lies 0.3 of a level above 100
+    let level = 100.3 / 255.0;
+    let flat = image::ImageBuffer::from_pixel(64, 64, image::Rgba([level, level, level, 1.0]));
+    let mean_level = |quantization: image_kernel::Quantization| {
+        let mut kernel =
+            image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba32F(flat.clone()));
+        // The levels are sRGB encoded already
+        kernel.linear = false;
+        kernel.quantization = quantization;
+        let rgba = kernel.to_rgba8();
+        assert!(rgba.pixels().all(|pixel| pixel[3] == 0xFF));
+        rgba.pixels().map(|pixel| pixel[0] as f32).sum::<f32>() / (64.0 * 64.0)
+    };
+
+    assert_eq!(mean_level(image_kernel::Quantization::Truncate), 100.0);
+    assert_eq!(
+        mean_level(image_kernel::Quantization::default()),
+        mean_level(image_kernel::Quantization::Truncate)
+    );
+    assert_eq!(mean_level(image_kernel::Quantization::Round), 100.0);
+    // Truncation keeps the levels that went through the sRGB transfer function
+    let ramp = image::ImageBuffer::from_fn(256, 1, |x, _| image::Luma([x as u8]));
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageLuma8(ramp));
+    kernel.decode_srgb();
+    kernel.encode_srgb();
+    for (x, pixel) in kernel.to_rgba8().pixels().enumerate() {
+        assert_eq!(pixel[0] as usize, x);
+    }
+    // Dithering keeps the fraction on average instead of banding
+    assert!((mean_level(image_kernel::Quantization::Bayer) - 100.3).abs() < 0.05);
+    assert!((mean_level(image_kernel::Quantization::BlueNoise(7)) - 100.3).abs() < 0.05);
+}
+
+#[test]
+fn test_blue_noise_is_seeded() {
+    let ramp = image::ImageBuffer::from_fn(64, 64, |x, y| {
+        let value = (x + y) as f32 / 1024.0 + 0.2;
+        image::Rgb([value, value, value])
+    });
+    let render = |seed: u64| {
+        let mut kernel =
+            image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgb32F(ramp.clone()));
+        kernel.quantization = image_kernel::Quantization::BlueNoise(seed);
+        kernel.to_rgba8().into_raw()
+    };
+
+    assert_eq!(render(1), render(1));
+    assert_ne!(render(1), render(2));
+}
+
+#[test]
+fn test_animated_gif_keeps_frames_and_timing() {
+    let frames: Vec<animation::Frame> = (0..3u32)
+        .map(|i| {
+            let buffer = image::ImageBuffer::from_fn(16, 16, |x, y| {
+                if x < 4 && y < 4 {
+                    image::Rgba([0, 0, 0, 0])
+                } else if (x + i * 4) % 16 < 8 {
+                    image::Rgba([220, 40, 40, 0xFF])
+                } else {
+                    image::Rgba([250, 250, 250, 0xFF])
+                }
+            });
+            animation::Frame {
+                image: image::DynamicImage::ImageRgba8(buffer),
+                delay: image::Delay::from_numer_denom_ms(100 * (i + 1), 1),
+            }
+        })
+        .collect();
+    let input = std::env::temp_dir().join("anime4k-animated-in.gif");
+    animation::Animation {
+        frames: frames.clone(),
+        repeat: image::codecs::gif::Repeat::Finite(3),
+    }
+    .write_gif(input.to_str().unwrap(), palette::Dither::None)
+    .unwrap();
+
+    let source = image_kernel::read(input.to_str().unwrap(), None).unwrap();
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::new_rgba8(1, 1));
+    let upscaled = source
+        .animation
+        .unwrap()
+        .upscale(&mut kernel, &pipeline::Pipeline::default(), None)
+        .unwrap();
+    let output = std::env::temp_dir().join("anime4k-animated-out.gif");
+    upscaled
+        .write_gif(output.to_str().unwrap(), palette::Dither::ErrorDiffusion)
+        .unwrap();
+
+    let saved = animation::Animation::read_gif(&std::fs::read(&output).unwrap()).unwrap();
+    assert!(matches!(
+        saved.repeat,
+        image::codecs::gif::Repeat::Finite(3)
+    ));
+    assert_eq!(saved.frames.len(), 3);
+    for (frame, original) in saved.frames.iter().zip(frames.iter()) {
+        let buffer = frame.image.to_rgba8();
+        assert_eq!(frame.delay, original.delay);
+        assert_eq!(buffer.dimensions(), (32, 32));
+        assert_eq!(buffer.get_pixel(2, 2)[3], 0);
+        assert_eq!(buffer.get_pixel(20, 20)[3], 0xFF);
+    }
+}
+
+#[test]
+fn test_gif_rejects_frames_over_65535_pixels() {
+    let buffer = image::ImageBuffer::from_pixel(65536, 1, image::Rgba([0u8, 0, 0, 0xFF]));
+    let output = std::env::temp_dir().join("anime4k-too-wide.gif");
+    let result = animation::Animation {
+        frames: vec![animation::Frame {
+            image: image::DynamicImage::ImageRgba8(buffer),
+            delay: image::Delay::from_numer_denom_ms(0, 1),
+        }],
+        repeat: image::codecs::gif::Repeat::Infinite,
+    }
+    .write_gif(output.to_str().unwrap(), palette::Dither::None);
+
+    assert!(matches!(result, Err(image::ImageError::Encoding(_))));
+}
+
+#[test]
+fn test_apng_keeps_frames_and_timing() {
+    let input = std::env::temp_dir().join("anime4k-animated-in.png");
+    {
+        let file = std::fs::File::create(&input).unwrap();
+        let mut encoder = png::Encoder::new(file, 16, 16);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_animated(2, 2).unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        writer.set_frame_delay(1, 10).unwrap();
+        writer
+            .write_image_data(&[200, 30, 30, 0xFF].repeat(16 * 16))
+            .unwrap();
+        // A blue square over the bottom right quarter, the rest shows through
+        writer.set_frame_delay(3, 20).unwrap();
+        writer.set_frame_dimension(8, 8).unwrap();
+        writer.set_frame_position(8, 8).unwrap();
+        writer.set_blend_op(png::BlendOp::Over).unwrap();
+        writer
+            .write_image_data(&[30, 30, 200, 0xFF].repeat(8 * 8))
+            .unwrap();
+        writer.finish().unwrap();
+    }
+
+    let source = image_kernel::read(input.to_str().unwrap(), None).unwrap();
+    let animation = source.animation.unwrap();
+    assert_eq!(animation.frames.len(), 2);
+    let second = animation.frames[1].image.to_rgba8();
+    assert_eq!(second.get_pixel(2, 2)[0], 200);
+    assert_eq!(second.get_pixel(12, 12)[2], 200);
+
+    let output = std::env::temp_dir().join("anime4k-animated-out.png");
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::new_rgba8(1, 1));
+    animation
+        .upscale(&mut kernel, &pipeline::Pipeline::default(), None)
+        .unwrap()
+        .write_apng(
+            output.to_str().unwrap(),
+            None,
+            &metadata::Metadata::default(),
+            &codec::Encoding::default(),
+        )
+        .unwrap();
+
+    let encoded = std::fs::read(&output).unwrap();
+    let mut reader = png::Decoder::new(&encoded[..]).read_info().unwrap();
+    let control = reader.info().animation_control.unwrap();
+    assert_eq!((control.num_frames, control.num_plays), (2, 2));
+    let mut delays = Vec::new();
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    while reader.next_frame(&mut buffer).is_ok() {
+        let frame = reader.info().frame_control.unwrap();
+        assert_eq!((frame.width, frame.height), (32, 32));
+        delays.push((frame.delay_num, frame.delay_den));
+    }
+    assert_eq!(delays, vec![(1, 10), (3, 20)]);
+}
+
+#[test]
+fn test_animation_converts_and_carries_profile_and_metadata() {
+    let mut encoded = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut encoded, 8, 8);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder
+            .add_text_chunk("Title".to_string(), "Sticker".to_string())
+            .unwrap();
+        encoder.set_animated(2, 0).unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        for level in [60u8, 180] {
+            writer
+                .write_image_data(&[level, 200, 40].repeat(8 * 8))
+                .unwrap();
+        }
+        writer.finish().unwrap();
+    }
+    let input = std::env::temp_dir().join("anime4k-animated-p3-in.png");
+    std::fs::write(
+        &input,
+        icc::embed(encoded, &icc::load("display-p3").unwrap()),
+    )
+    .unwrap();
+
+    let source = image_kernel::read(input.to_str().unwrap(), None).unwrap();
+    let animation = source.animation.as_ref().unwrap();
+    let upscale = |target_profile: Option<&[u8]>| {
+        let mut kernel =
+            image_kernel::ImageKernel::from_image(image::DynamicImage::new_rgba8(1, 1));
+        kernel.icc_profile = source.icc_profile.clone();
+        let upscaled = animation
+            .upscale(&mut kernel, &pipeline::Pipeline::default(), target_profile)
+            .unwrap();
+        (upscaled, kernel.icc_profile)
+    };
+    let (plain, _) = upscale(None);
+    let srgb = icc::load("srgb").unwrap();
+    let (converted, profile) = upscale(Some(&srgb));
+    assert_eq!(profile.as_deref(), Some(&srgb[..]));
+    // Every frame is converted, not the first one alone
+    for (plain, converted) in plain.frames.iter().zip(converted.frames.iter()) {
+        assert_ne!(plain.image.to_rgba8(), converted.image.to_rgba8());
+    }
+
+    let output = std::env::temp_dir().join("anime4k-animated-p3-out.png");
+    converted
+        .write_apng(
+            output.to_str().unwrap(),
+            profile.as_deref(),
+            &source.metadata,
+            &codec::Encoding::default(),
+        )
+        .unwrap();
+    let encoded = std::fs::read(&output).unwrap();
+    assert_eq!(icc::extract(&encoded), Some(srgb));
+    let reader = png::Decoder::new(&encoded[..]).read_info().unwrap();
+    assert_eq!(reader.info().animation_control.unwrap().num_frames, 2);
+    assert!(reader
+        .info()
+        .uncompressed_latin1_text
+        .iter()
+        .any(|chunk| chunk.keyword == "Title" && chunk.text == "Sticker"));
+}
+
+#[test]
+fn test_16_bit_apng_stays_16_bit() {
+    let input = std::env::temp_dir().join("anime4k-animated-16-in.png");
+    {
+        let file = std::fs::File::create(&input).unwrap();
+        let mut encoder = png::Encoder::new(file, 8, 8);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        encoder.set_animated(2, 0).unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        for level in [0x4321u16, 0x8765] {
+            let frame: Vec<u8> = (0..8 * 8)
+                .flat_map(|i| [level, level + i * 64, 0x1234])
+                .flat_map(|sample| sample.to_be_bytes())
+                .collect();
+            writer.write_image_data(&frame).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    let source = image_kernel::read(input.to_str().unwrap(), None).unwrap();
+    let animation = source.animation.unwrap();
+    assert!(matches!(
+        animation.frames[1].image,
+        image::DynamicImage::ImageRgba16(_)
+    ));
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::new_rgba8(1, 1));
+    let output = std::env::temp_dir().join("anime4k-animated-16-out.png");
+    animation
+        .upscale(&mut kernel, &pipeline::Pipeline::default(), None)
+        .unwrap()
+        .write_apng(
+            output.to_str().unwrap(),
+            None,
+            &metadata::Metadata::default(),
+            &codec::Encoding::default(),
+        )
+        .unwrap();
+
+    let encoded = std::fs::read(&output).unwrap();
+    let mut reader = png::Decoder::new(&encoded[..]).read_info().unwrap();
+    assert_eq!(reader.info().bit_depth, png::BitDepth::Sixteen);
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let mut frames = 0;
+    while reader.next_frame(&mut buffer).is_ok() {
+        frames += 1;
+    }
+    assert_eq!(frames, 2);
+    // Not 8 bit values widened to 16
+    assert!(buffer.chunks_exact(2).any(|sample| sample[0] != sample[1]));
+}
+
+#[test]
+fn test_webp_and_avif_output() {
+    let frame = image::ImageBuffer::from_fn(32, 32, |x, y| {
+        image::Rgba([
+            (x * 8) as u8,
+            (y * 8) as u8,
+            0x80,
+            if x < 4 { 0 } else { 0xFF },
+        ])
+    });
+    let mut kernel =
+        image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba8(frame.clone()));
+
+    kernel.encoding.lossless = true;
+    let output = std::env::temp_dir().join("anime4k-lossless.webp");
+    kernel.save(output.to_str().unwrap(), None).unwrap();
+    assert_eq!(image::open(&output).unwrap().into_rgba8(), frame);
+
+    kernel.encoding.lossless = false;
+    let output = std::env::temp_dir().join("anime4k-lossy.webp");
+    kernel.save(output.to_str().unwrap(), None).unwrap();
+    let saved = image::open(&output).unwrap().into_rgba8();
+    assert_eq!(saved.dimensions(), (32, 32));
+    assert_eq!(saved.get_pixel(0, 16)[3], 0);
+    assert!((saved.get_pixel(20, 16)[0] as i32 - 160).abs() < 16);
+
+    let output = std::env::temp_dir().join("anime4k-out.avif");
+    let sizes: Vec<usize> = [20.0, 90.0]
+        .iter()
+        .map(|&quality| {
+            kernel.encoding.quality = quality;
+            kernel.save(output.to_str().unwrap(), None).unwrap();
+            let encoded = std::fs::read(&output).unwrap();
+            assert_eq!(&encoded[4..12], b"ftypavif");
+            encoded.len()
+        })
+        .collect();
+    assert!(sizes[0] < sizes[1]);
+
+    kernel.encoding.lossless = true;
+    assert!(kernel.save(output.to_str().unwrap(), None).is_err());
+}
+
+#[test]
+#[cfg(feature = "avif-decode")]
+fn test_avif_input() {
+    let frame = image::ImageBuffer::from_fn(32, 32, |x, y| {
+        image::Rgba([
+            (x * 8) as u8,
+            (y * 8) as u8,
+            0x80,
+            if x < 4 { 0 } else { 0xFF },
+        ])
+    });
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba8(frame));
+    kernel.encoding.quality = 100.0;
+    let output = std::env::temp_dir().join("anime4k-in.avif");
+    kernel.save(output.to_str().unwrap(), None).unwrap();
+
+    let source = image_kernel::read(output.to_str().unwrap(), None).unwrap();
+    assert!(source.image.color().has_alpha());
+    let decoded = source.image.into_rgba8();
+    assert_eq!(decoded.dimensions(), (32, 32));
+    assert_eq!(decoded.get_pixel(0, 16)[3], 0);
+    let pixel = decoded.get_pixel(20, 12);
+    assert_eq!(pixel[3], 0xFF);
+    assert!((pixel[0] as i32 - 160).abs() < 8);
+    assert!((pixel[1] as i32 - 96).abs() < 8);
+    assert!((pixel[2] as i32 - 0x80).abs() < 8);
+}
+
+#[test]
+fn test_jpeg_and_png_options() {
+    let frame = image::ImageBuffer::from_fn(64, 64, |x, y| {
+        image::Rgba([
+            (x * 4) as u8,
+            (y * 4) as u8,
+            0x40,
+            if x < 32 { 0 } else { 0xFF },
+        ])
+    });
+    let mut kernel =
+        image_kernel::ImageKernel::from_image(image::DynamicImage::ImageRgba8(frame.clone()));
+
+    // JPEG has no alpha, the transparent half takes the background
+    kernel.encoding.background = image::Rgba([0xFF, 0, 0, 0xFF]);
+    kernel.encoding.jpeg_subsampling = codec::ChromaSubsampling::Yuv444;
+    kernel.encoding.jpeg_progressive = true;
+    let output = std::env::temp_dir().join("anime4k-options.jpg");
+    kernel.save(output.to_str().unwrap(), None).unwrap();
+    let encoded = std::fs::read(&output).unwrap();
+    assert!(encoded.windows(2).any(|marker| marker == [0xFF, 0xC2]));
+    let saved = image::load_from_memory(&encoded).unwrap().into_rgb8();
+    let under = saved.get_pixel(8, 8);
+    assert!(under[0] > 240 && under[1] < 16 && under[2] < 16);
+    assert!((saved.get_pixel(48, 8)[0] as i32 - 192).abs() < 8);
+
+    let sizes: Vec<usize> = [
+        (codec::PngCompression::Fast, codec::PngFilter::None),
+        (codec::PngCompression::Best, codec::PngFilter::Adaptive),
+    ]
+    .iter()
+    .map(|&(compression, filter)| {
+        kernel.encoding.png_compression = compression;
+        kernel.encoding.png_filter = filter;
+        let output = std::env::temp_dir().join("anime4k-options.png");
+        kernel.save(output.to_str().unwrap(), None).unwrap();
+        assert_eq!(image::open(&output).unwrap().into_rgba8(), frame);
+        std::fs::metadata(&output).unwrap().len() as usize
+    })
+    .collect();
+    assert!(sizes[1] < sizes[0]);
+}
+
+#[test]
+fn test_metadata_is_carried_and_adjusted() {
+    use img_parts::ImageEXIF;
+
+    // Little endian EXIF: orientation 6 in the first IFD at 8, which points
+    // to an Exif IFD at 38 with the pixel size
+    let entry = |tag: u16, kind: u16, value: u32| {
+        let mut entry = Vec::new();
+        entry.extend_from_slice(&tag.to_le_bytes());
+        entry.extend_from_slice(&kind.to_le_bytes());
+        entry.extend_from_slice(&1u32.to_le_bytes());
+        entry.extend_from_slice(&value.to_le_bytes());
+        entry
+    };
+    let exif = [
+        b"II\x2A\x00\x08\x00\x00\x00\x02\x00".to_vec(),
+        entry(0x0112, 3, 6),
+        entry(0x8769, 4, 38),
+        vec![0, 0, 0, 0, 2, 0],
+        entry(0xA002, 4, 8),
+        entry(0xA003, 4, 4),
+        vec![0, 0, 0, 0],
+    ]
+    .concat();
+
+    // A wide image stored sideways: left half dark, right half light
+    let frame =
+        image::ImageBuffer::from_fn(8, 4, |x, _| image::Rgb([if x < 4 { 20u8 } else { 230 }; 3]));
+    let mut encoded = Vec::new();
+    let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut encoded, 95);
+    encoder.set_pixel_density(image::codecs::jpeg::PixelDensity::dpi(72));
+    encoder.encode_image(&frame).unwrap();
+    let mut jpeg = img_parts::jpeg::Jpeg::from_bytes(encoded.into()).unwrap();
+    jpeg.set_exif(Some(exif.into()));
+    let input = std::env::temp_dir().join("anime4k-metadata-in.jpg");
+    std::fs::write(&input, jpeg.encoder().bytes()).unwrap();
+
+    let source = image_kernel::read(input.to_str().unwrap(), None).unwrap();
+    // Orientation 6 turns it a quarter clockwise, so the dark half ends up on top
+    assert_eq!((source.image.width(), source.image.height()), (4, 8));
+    assert!(source.image.to_luma8().get_pixel(2, 1)[0] < 60);
+    let mut kernel = image_kernel::ImageKernel::from_image(source.image);
+    kernel.metadata = source.metadata;
+    pipeline::Pipeline::default().run(&mut kernel);
+    let output = std::env::temp_dir().join("anime4k-metadata-out.png");
+    kernel.save(output.to_str().unwrap(), None).unwrap();
+
+    let saved = img_parts::png::Png::from_bytes(std::fs::read(&output).unwrap().into()).unwrap();
+    let phys = saved.chunk_by_type(*b"pHYs").unwrap().contents().to_vec();
+    // 144 dpi in pixels per meter, twice the source density
+    assert_eq!(&phys[..4], &5669u32.to_be_bytes());
+    let exif = saved.exif().unwrap();
+    let tag = |offset: usize| {
+        u32::from_le_bytes([
+            exif[offset],
+            exif[offset + 1],
+            exif[offset + 2],
+            exif[offset + 3],
+        ])
+    };
+    assert_eq!(tag(18) & 0xFFFF, 1);
+    assert_eq!((tag(48), tag(60)), (8, 16));
+
+    // Text chunks go from PNG to PNG untouched
+    let mut png = img_parts::png::Png::from_bytes(std::fs::read(&output).unwrap().into()).unwrap();
+    let chunk =
+        img_parts::png::PngChunk::new(*b"tEXt", img_parts::Bytes::from_static(b"Author\0Someone"));
+    png.chunks_mut().insert(1, chunk);
+    let input = std::env::temp_dir().join("anime4k-metadata-in.png");
+    std::fs::write(&input, png.encoder().bytes()).unwrap();
+    let source = image_kernel::read(input.to_str().unwrap(), None).unwrap();
+    assert_eq!(
+        source.metadata.text,
+        vec![(*b"tEXt", b"Author\0Someone".to_vec())]
+    );
+}
+
+#[test]
+fn test_explicit_formats() {
+    let frame = image::ImageBuffer::from_pixel(4, 4, image::Rgb([10u8, 200, 30]));
+    let mut encoded = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::ImageRgb8(frame)
+        .write_to(&mut encoded, image::ImageFormat::Png)
+        .unwrap();
+    let input = std::env::temp_dir().join("anime4k-format-in.dat");
+    std::fs::write(&input, encoded.into_inner()).unwrap();
+
+    let guessed = image_kernel::read(input.to_str().unwrap(), None).unwrap();
+    let given = image_kernel::read(input.to_str().unwrap(), Some(image::ImageFormat::Png)).unwrap();
+    assert_eq!(guessed.image, given.image);
+    assert!(image_kernel::read(input.to_str().unwrap(), Some(image::ImageFormat::Gif)).is_err());
+
+    let kernel = image_kernel::ImageKernel::from_image(given.image);
+    let output = std::env::temp_dir().join("anime4k-format-out.dat");
+    assert!(kernel.save(output.to_str().unwrap(), None).is_err());
+    kernel
+        .save(output.to_str().unwrap(), Some(image::ImageFormat::Bmp))
+        .unwrap();
+    let saved = std::fs::read(&output).unwrap();
+    assert_eq!(
+        image::guess_format(&saved).unwrap(),
+        image::ImageFormat::Bmp
+    );
+}
+
+#[test]
+fn test_raw_buffers() {
+    let layout = "3x2:bgra8:16".parse::<raw::RawLayout>().unwrap();
+    assert_eq!(layout.stride, 16);
+    assert_eq!(layout.buffer_len(), 28);
+    assert!("3x2:bgra8:8".parse::<raw::RawLayout>().is_err());
+    assert!("3x2".parse::<raw::RawLayout>().is_err());
+    assert!("3x2:yuv".parse::<raw::RawLayout>().is_err());
+
+    // Two rows of three BGRA pixels, padded to 16 bytes
+    let mut buffer = Vec::new();
+    for y in 0..2u8 {
+        for x in 0..3u8 {
+            buffer.extend_from_slice(&[x * 10, y * 10, 200, 255 - x]);
+        }
+        if y == 0 {
+            buffer.extend_from_slice(&[0xAA; 4]);
+        }
+    }
+    let input = std::env::temp_dir().join("anime4k-raw-in.bgra");
+    std::fs::write(&input, &buffer).unwrap();
+    let image = raw::read(input.to_str().unwrap(), &layout).unwrap();
+    assert_eq!(image.to_rgba8().get_pixel(2, 1).0, [200, 10, 20, 253]);
+    assert!(raw::read(input.to_str().unwrap(), &"3x3:bgra8".parse().unwrap()).is_err());
+
+    let kernel = image_kernel::ImageKernel::from_image(image.clone());
+    let output = std::env::temp_dir().join("anime4k-raw-out.rgb");
+    kernel
+        .save_raw(output.to_str().unwrap(), raw::RawFormat::Rgb8, Some(12))
+        .unwrap();
+    assert_eq!(std::fs::metadata(&output).unwrap().len(), 21);
+    let saved = raw::read(output.to_str().unwrap(), &"3x2:rgb8:12".parse().unwrap()).unwrap();
+    assert_eq!(saved, image::DynamicImage::ImageRgb8(image.to_rgb8()));
+}
+
+#[test]
+fn test_y4m_stream() {
+    let header = y4m::Header::parse("W5 H3 F25:1 Ip A1:1 C422p10 XCOLORRANGE=FULL").unwrap();
+    assert_eq!(header.subsampling, codec::ChromaSubsampling::Yuv422);
+    assert_eq!(header.bit_depth, 10);
+    assert!(header.full_range);
+    assert_eq!(header.chroma_size(), (3, 3));
+    assert_eq!(header.frame_len(), (15 + 2 * 9) * 2);
+    assert!(y4m::Header::parse("W5 H3 Cmono").is_err());
+    assert!(y4m::Header::parse("H3 C420").is_err());
+
+    // A stretch keeps the display shape through the pixel aspect ratio
+    let mut line = Vec::new();
+    header.resized(10, 3).write(&mut line).unwrap();
+    assert_eq!(
+        String::from_utf8(line).unwrap(),
+        "YUV4MPEG2 W10 H3 F25:1 Ip A1:2 C422p10 XCOLORRANGE=FULL\n"
+    );
+
+    // Two 4:4:4 frames with distinct colors come back as they went in when
+    // nothing is scaled or pushed
+    let mut stream = b"YUV4MPEG2 W4 H2 F30000:1001 C444\n".to_vec();
+    let mut frames = Vec::new();
+    for frame in 0..2u8 {
+        let data: Vec<u8> = (0..24u8).map(|i| 60 + i * 5 + frame * 7).collect();
+        stream.extend_from_slice(b"FRAME\n");
+        stream.extend_from_slice(&data);
+        frames.push(data);
+    }
+    let input = std::env::temp_dir().join("anime4k-stream-in.y4m");
+    let output = std::env::temp_dir().join("anime4k-stream-out.y4m");
+    std::fs::write(&input, &stream).unwrap();
+    assert!(y4m::is_y4m(input.to_str().unwrap()));
+    let pipeline = pipeline::Pipeline {
+        resize: pipeline::Resize::Factor { x: 1.0, y: 1.0 },
+        iteration: 0,
+        ..pipeline::Pipeline::default()
+    };
+    y4m::upscale(input.to_str().unwrap(), output.to_str().unwrap(), &pipeline).unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), stream);
+
+    // 4:2:0 is read and written with half size chroma at the new size
+    let mut stream = b"YUV4MPEG2 W4 H2 C420jpeg\nFRAME\n".to_vec();
+    stream.extend_from_slice(&[128; 4 * 2 + 2 * 2]);
+    std::fs::write(&input, &stream).unwrap();
+    y4m::upscale(
+        input.to_str().unwrap(),
+        output.to_str().unwrap(),
+        &pipeline::Pipeline::default(),
+    )
+    .unwrap();
+    let mut reader = std::io::BufReader::new(std::fs::File::open(&output).unwrap());
+    let header = y4m::read_header(&mut reader).unwrap();
+    assert_eq!((header.width, header.height), (8, 4));
+    let planes = y4m::read_frame(&mut reader, &header, image_kernel::LumaModel::Bt601)
+        .unwrap()
+        .unwrap();
+    assert_eq!(planes.cb.dimensions(), (4, 2));
+    assert!(
+        y4m::read_frame(&mut reader, &header, image_kernel::LumaModel::Bt601)
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[test]
+fn test_frame_sequence() {
+    assert_eq!(
+        sequence::frame_path("in/%06d.png", 42).unwrap(),
+        "in/000042.png"
+    );
+    assert_eq!(sequence::frame_path("%d%%.png", 7).unwrap(), "7%.png");
+    assert!(sequence::frame_path("in/frame.png", 1).is_err());
+    assert!(sequence::frame_path("%d-%d.png", 1).is_err());
+
+    let directory = std::env::temp_dir().join("anime4k-sequence");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(directory.join("in")).unwrap();
+    for index in 1..=3u8 {
+        let frame = image::ImageBuffer::from_pixel(4, 4, image::Rgb([index * 60, 100, 30]));
+        image::DynamicImage::ImageRgb8(frame)
+            .save(directory.join(format!("in/{:03}.png", index)))
+            .unwrap();
+    }
+    let pattern = |name: &str| directory.join(name).to_str().unwrap().to_string();
+    let mut sequence = sequence::Sequence {
+        input: pattern("in/%03d.png"),
+        output: pattern("out/%03d.png"),
+        input_format: None,
+        output_format: image::ImageFormat::Png,
+        start: None,
+        end: None,
+        strip: false,
+    };
+    let pipeline = pipeline::Pipeline::default();
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::new_rgba8(1, 1));
+    let progress = sequence.upscale(&mut kernel, &pipeline, None).unwrap();
+    assert_eq!((progress.upscaled, progress.skipped), (3, 0));
+    assert_eq!(
+        image::open(directory.join("out/003.png")).unwrap().width(),
+        8
+    );
+
+    // A frame cut off by a crash only left its .part- file, and an empty
+    // one has nothing in it. Both are done again, the complete one is kept.
+    let second = directory.join("out/002.png");
+    let partial = directory.join("out/.part-002.png");
+    let third = directory.join("out/003.png");
+    let encoded = std::fs::read(&second).unwrap();
+    std::fs::remove_file(&second).unwrap();
+    std::fs::write(&partial, &encoded[..encoded.len() / 2]).unwrap();
+    std::fs::write(&third, b"").unwrap();
+    assert!(!sequence::is_done(second.to_str().unwrap()));
+    assert!(!sequence::is_done(third.to_str().unwrap()));
+    let progress = sequence.upscale(&mut kernel, &pipeline, None).unwrap();
+    assert_eq!((progress.upscaled, progress.skipped), (2, 1));
+    assert!(!partial.exists());
+    for frame in [&second, &third] {
+        assert!(sequence::is_done(frame.to_str().unwrap()));
+        assert_eq!(image::open(frame).unwrap().width(), 8);
+    }
+
+    sequence.start = Some(2);
+    sequence.end = Some(4);
+    assert!(sequence.upscale(&mut kernel, &pipeline, None).is_err());
+}
+
+#[test]
+fn test_sequence_keeps_grayscale_frames_single_channel() {
+    let directory = std::env::temp_dir().join("anime4k-sequence-gray");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(directory.join("in")).unwrap();
+    let page = image::open("assets/eye-in.png").unwrap().to_luma8();
+    image::DynamicImage::ImageLuma8(page.clone())
+        .save(directory.join("in/001.png"))
+        .unwrap();
+    image::DynamicImage::ImageRgb8(image::DynamicImage::ImageLuma8(page.clone()).to_rgb8())
+        .save(directory.join("in/002.png"))
+        .unwrap();
+    let pattern = |name: &str| directory.join(name).to_str().unwrap().to_string();
+    let sequence = sequence::Sequence {
+        input: pattern("in/%03d.png"),
+        output: pattern("out/%03d.png"),
+        input_format: None,
+        output_format: image::ImageFormat::Png,
+        start: None,
+        end: None,
+        strip: false,
+    };
+    let mut kernel = image_kernel::ImageKernel::from_image(image::DynamicImage::new_rgba8(1, 1));
+    sequence
+        .upscale(&mut kernel, &pipeline::Pipeline::default(), None)
+        .unwrap();
+
+    let gray = image::open(directory.join("out/001.png")).unwrap();
+    let color = image::open(directory.join("out/002.png")).unwrap();
+    assert_eq!(gray.color(), image::ColorType::L8);
+    assert_eq!(color.color(), image::ColorType::Rgba8);
+    for (gray, color) in gray.to_luma8().pixels().zip(color.to_rgba8().pixels()) {
+        assert!((gray[0] as i32 - color[0] as i32).abs() <= 1);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_video_through_ffmpeg_stub() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let directory = std::env::temp_dir().join("anime4k-video");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let mut stream = b"YUV4MPEG2 W4 H2 F24:1 C444\nFRAME\n".to_vec();
+    stream.extend_from_slice(&[128; 4 * 2 * 3]);
+    let fixture = directory.join("decoded.y4m");
+    std::fs::write(&fixture, &stream).unwrap();
+
+    // Decodes to the fixture when writing to stdout, otherwise records its
+    // arguments and keeps what it is given
+    let log = directory.join("encoder.log");
+    let stub = directory.join("ffmpeg");
+    std::fs::write(
+        &stub,
+        format!(
+            "#!/bin/sh\nfor last; do :; done\nif [ \"$last\" = \"-\" ]; then\n  cat '{}'\nelse\n  echo \"$@\" > '{}'\n  cat > \"$last\"\nfi\n",
+            fixture.display(),
+            log.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&stub, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = directory.join("out.mkv");
+    let ffmpeg = video::Ffmpeg {
+        program: stub.to_str().unwrap().to_string(),
+        encoder_args: vec!["-c:v".to_string(), "ffv1".to_string()],
+    };
+    ffmpeg
+        .transcode(
+            "in.mkv",
+            output.to_str().unwrap(),
+            &pipeline::Pipeline::default(),
+        )
+        .unwrap();
+    let mut reader = std::io::BufReader::new(std::fs::File::open(&output).unwrap());
+    let header = y4m::read_header(&mut reader).unwrap();
+    assert_eq!((header.width, header.height), (8, 4));
+    let args = std::fs::read_to_string(&log).unwrap();
+    assert!(args.contains("-i - -i in.mkv -map 0:v -map 1:a? -map 1:s?"));
+    assert!(args.contains("-c:a copy -c:s copy -c:v ffv1"));
+
+    let failing = directory.join("failing");
+    std::fs::write(&failing, "#!/bin/sh\nexit 1\n").unwrap();
+    std::fs::set_permissions(&failing, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let error = video::Ffmpeg {
+        program: failing.to_str().unwrap().to_string(),
+        ..video::Ffmpeg::default()
+    }
+    .transcode(
+        "in.mkv",
+        output.to_str().unwrap(),
+        &pipeline::Pipeline::default(),
+    )
+    .unwrap_err();
+    assert!(error.contains("couldn't decode in.mkv"));
+    assert!(video::Ffmpeg {
+        program: directory.join("missing").to_str().unwrap().to_string(),
+        ..video::Ffmpeg::default()
+    }
+    .transcode(
+        "in.mkv",
+        output.to_str().unwrap(),
+        &pipeline::Pipeline::default(),
+    )
+    .is_err());
 }
@@ -0,0 +1,106 @@
+use crate::pipeline::Pipeline;
+use crate::y4m;
+use std::process::{Child, Command, Stdio};
+
+/// How the ffmpeg binary that decodes and encodes the video is run
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ffmpeg {
+    /// Path of the binary, looked up in `PATH` when it is a bare name
+    pub program: String,
+    /// Arguments that pick and tune the video encoder, such as `-c:v libx264`
+    pub encoder_args: Vec<String>,
+}
+
+impl Default for Ffmpeg {
+    fn default() -> Ffmpeg {
+        Ffmpeg {
+            program: "ffmpeg".to_string(),
+            encoder_args: Vec::new(),
+        }
+    }
+}
+
+impl Ffmpeg {
+    /// Decodes the first video stream of `input` to a Y4M stream on stdout.
+    /// `-strict -1` lets 4:2:2, 4:4:4 and high bit depth through as they are.
+    fn decoder(&self, input: &str) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .args(["-v", "error", "-nostdin", "-i", input])
+            .args(["-map", "0:v:0", "-f", "yuv4mpegpipe", "-strict", "-1", "-"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+        command
+    }
+
+    /// Encodes the Y4M stream on stdin to `output`, copying the audio,
+    /// subtitles, chapters and metadata of `input` along
+    fn encoder(&self, input: &str, output: &str) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .args([
+                "-v",
+                "error",
+                "-y",
+                "-f",
+                "yuv4mpegpipe",
+                "-i",
+                "-",
+                "-i",
+                input,
+            ])
+            .args(["-map", "0:v", "-map", "1:a?", "-map", "1:s?"])
+            .args(["-map_metadata", "1", "-map_chapters", "1"])
+            .args(["-c:a", "copy", "-c:s", "copy"])
+            .args(&self.encoder_args)
+            .arg(output)
+            .stdin(Stdio::piped());
+        command
+    }
+
+    fn spawn(&self, mut command: Command) -> Result<Child, String> {
+        command
+            .spawn()
+            .map_err(|e| format!("Can't run {}: {}", self.program, e))
+    }
+
+    /// Runs `pipeline` on every frame of the video in `input` and encodes the
+    /// result to `output`. The frames go through pipes, never touching the disk.
+    pub fn transcode(&self, input: &str, output: &str, pipeline: &Pipeline) -> Result<(), String> {
+        let mut decoder = self.spawn(self.decoder(input))?;
+        let mut encoder = match self.spawn(self.encoder(input, output)) {
+            Ok(encoder) => encoder,
+            Err(e) => {
+                let _ = decoder.kill();
+                let _ = decoder.wait();
+                return Err(e);
+            }
+        };
+
+        let reader = std::io::BufReader::new(decoder.stdout.take().expect("Decoder has no stdout"));
+        let writer = std::io::BufWriter::new(encoder.stdin.take().expect("Encoder has no stdin"));
+        // Dropping the writer closes the pipe, which ends the encoder
+        let streamed = y4m::upscale_stream(reader, writer, pipeline);
+        if streamed.is_err() {
+            // The decoder may be blocked on a pipe nobody reads anymore
+            let _ = decoder.kill();
+        }
+        let decoded = decoder.wait().map_err(|e| e.to_string())?;
+        let encoded = encoder.wait().map_err(|e| e.to_string())?;
+
+        // A decoder killed above has no exit code, the stream error tells why
+        if decoded.code().is_some_and(|code| code != 0) {
+            return Err(format!(
+                "{} couldn't decode {}: {}",
+                self.program, input, decoded
+            ));
+        }
+        if !encoded.success() {
+            return Err(format!(
+                "{} couldn't encode {}: {}",
+                self.program, output, encoded
+            ));
+        }
+        streamed.map_err(|e| format!("Can't upscale {}: {}", input, e))
+    }
+}
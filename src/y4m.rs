@@ -0,0 +1,356 @@
+use crate::codec::ChromaSubsampling;
+use crate::image_kernel::{self, BitDepth, LumaModel, YCbCrKernel};
+use crate::pipeline::Pipeline;
+use std::io::{BufRead, Read, Write};
+
+const MAGIC: &[u8] = b"YUV4MPEG2 ";
+
+type Plane = image::ImageBuffer<image::Luma<f32>, Vec<f32>>;
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Stream header of a YUV4MPEG2 file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub subsampling: ChromaSubsampling,
+    /// Bits per sample, samples above 8 bits taking two little endian bytes
+    pub bit_depth: u32,
+    /// Whether the samples use the whole range instead of the video levels
+    pub full_range: bool,
+    /// Every parameter as it came, written back with the new size
+    params: Vec<String>,
+}
+
+impl Header {
+    /// Parses the header line, without the magic and the newline
+    pub fn parse(line: &str) -> Result<Header, String> {
+        let params: Vec<String> = line.split_whitespace().map(String::from).collect();
+        let mut header = Header {
+            width: 0,
+            height: 0,
+            subsampling: ChromaSubsampling::Yuv420,
+            bit_depth: 8,
+            full_range: false,
+            params: params.clone(),
+        };
+        for param in &params {
+            let (tag, value) = split_param(param);
+            match tag {
+                'W' => {
+                    header.width = value
+                        .parse()
+                        .map_err(|_| format!("Invalid width {}", value))?
+                }
+                'H' => {
+                    header.height = value
+                        .parse()
+                        .map_err(|_| format!("Invalid height {}", value))?
+                }
+                'C' => {
+                    let (subsampling, bit_depth) = parse_colorspace(value)?;
+                    header.subsampling = subsampling;
+                    header.bit_depth = bit_depth;
+                }
+                'X' => match value {
+                    "COLORRANGE=FULL" => header.full_range = true,
+                    "COLORRANGE=LIMITED" => header.full_range = false,
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        if header.width == 0 || header.height == 0 {
+            return Err(format!("Missing frame size in {}", line));
+        }
+        Ok(header)
+    }
+
+    /// Size of the Cb and Cr planes
+    pub fn chroma_size(&self) -> (u32, u32) {
+        let half = |side: u32| side.div_ceil(2);
+        match self.subsampling {
+            ChromaSubsampling::Yuv444 => (self.width, self.height),
+            ChromaSubsampling::Yuv422 => (half(self.width), self.height),
+            ChromaSubsampling::Yuv420 => (half(self.width), half(self.height)),
+        }
+    }
+
+    fn sample_len(&self) -> usize {
+        if self.bit_depth > 8 {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Bytes of the three planes of a frame
+    pub fn frame_len(&self) -> usize {
+        let (chroma_width, chroma_height) = self.chroma_size();
+        (self.width as usize * self.height as usize
+            + 2 * chroma_width as usize * chroma_height as usize)
+            * self.sample_len()
+    }
+
+    /// The header of the stream scaled to `width` x `height`. The pixel
+    /// aspect ratio changes with a stretch, so that the picture keeps its
+    /// display shape.
+    pub fn resized(&self, width: u32, height: u32) -> Header {
+        let params = self
+            .params
+            .iter()
+            .map(|param| match split_param(param) {
+                ('W', _) => format!("W{}", width),
+                ('H', _) => format!("H{}", height),
+                ('A', ratio) => match parse_ratio(ratio) {
+                    Some((numer, denom)) if numer > 0 && denom > 0 => {
+                        let numer = numer * height as u64 * self.width as u64;
+                        let denom = denom * width as u64 * self.height as u64;
+                        let divisor = gcd(numer, denom);
+                        format!("A{}:{}", numer / divisor, denom / divisor)
+                    }
+                    _ => param.clone(),
+                },
+                _ => param.clone(),
+            })
+            .collect();
+        Header {
+            width,
+            height,
+            params,
+            ..self.clone()
+        }
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> std::io::Result<()> {
+        output.write_all(MAGIC)?;
+        output.write_all(self.params.join(" ").as_bytes())?;
+        output.write_all(b"\n")
+    }
+
+    /// Normalizes a sample to 0-1, video levels putting black at 0 and
+    /// neutral chroma at 0.5
+    fn decode(&self, value: u16, chroma: bool) -> f32 {
+        let shift = (1 << (self.bit_depth - 8)) as f32;
+        let value = value as f32;
+        match (self.full_range, chroma) {
+            (true, _) => value / ((1u32 << self.bit_depth) - 1) as f32,
+            (false, false) => (value - 16.0 * shift) / (219.0 * shift),
+            (false, true) => (value - 128.0 * shift) / (224.0 * shift) + 0.5,
+        }
+    }
+
+    /// Inverse of `decode`, rounded to the nearest level
+    fn encode(&self, value: f32, chroma: bool) -> u16 {
+        let shift = (1 << (self.bit_depth - 8)) as f32;
+        let max = ((1u32 << self.bit_depth) - 1) as f32;
+        let value = match (self.full_range, chroma) {
+            (true, _) => value * max,
+            (false, false) => value * 219.0 * shift + 16.0 * shift,
+            (false, true) => (value - 0.5) * 224.0 * shift + 128.0 * shift,
+        };
+        image_kernel::clamp(value.round(), 0.0, max) as u16
+    }
+}
+
+/// Splits a parameter into its tag letter and its value
+fn split_param(param: &str) -> (char, &str) {
+    let mut chars = param.chars();
+    let tag = chars.next().unwrap_or(' ');
+    (tag, chars.as_str())
+}
+
+/// Subsampling and depth of a `C` parameter, such as `420jpeg` or `444p10`
+fn parse_colorspace(s: &str) -> Result<(ChromaSubsampling, u32), String> {
+    let unsupported = || format!("Unsupported Y4M colorspace {}", s);
+    if s.len() < 3 || !s.is_char_boundary(3) {
+        return Err(unsupported());
+    }
+    let subsampling = s[..3]
+        .parse::<ChromaSubsampling>()
+        .map_err(|_| unsupported())?;
+    let bit_depth = match &s[3..] {
+        "" | "jpeg" | "paldv" | "mpeg2" => 8,
+        depth if depth.starts_with('p') => depth[1..].parse::<u32>().map_err(|_| unsupported())?,
+        _ => return Err(unsupported()),
+    };
+    if !(8..=16).contains(&bit_depth) {
+        return Err(unsupported());
+    }
+    Ok((subsampling, bit_depth))
+}
+
+fn parse_ratio(s: &str) -> Option<(u64, u64)> {
+    let mut parts = s.splitn(2, ':');
+    let numer = parts.next()?.parse().ok()?;
+    let denom = parts.next()?.parse().ok()?;
+    Some((numer, denom))
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let rest = a % b;
+        a = b;
+        b = rest;
+    }
+    a
+}
+
+/// Reads a line up to the newline, which is dropped, failing past `limit`
+/// bytes. Returns `None` at the end of the stream.
+fn read_line<R: BufRead>(input: &mut R, limit: u64) -> std::io::Result<Option<String>> {
+    let mut line = Vec::new();
+    input.take(limit).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("Unterminated Y4M header".to_string()));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid("Y4M header isn't text".to_string()))
+}
+
+/// Reads the stream header
+pub fn read_header<R: BufRead>(input: &mut R) -> std::io::Result<Header> {
+    let line = read_line(input, 4096)?.unwrap_or_default();
+    if !line.as_bytes().starts_with(MAGIC) {
+        return Err(invalid("Not a Y4M stream".to_string()));
+    }
+    Header::parse(&line[MAGIC.len()..]).map_err(invalid)
+}
+
+/// Reads the next frame as planes in the `luma_model` matrix, `None` once the
+/// stream ends
+pub fn read_frame<R: BufRead>(
+    input: &mut R,
+    header: &Header,
+    luma_model: LumaModel,
+) -> std::io::Result<Option<YCbCrKernel>> {
+    match read_line(input, 1024)? {
+        Some(line) if line.starts_with("FRAME") => {}
+        Some(line) => return Err(invalid(format!("Expected a Y4M frame, found {}", line))),
+        None => return Ok(None),
+    }
+    let mut data = vec![0; header.frame_len()];
+    input.read_exact(&mut data)?;
+
+    let sample_len = header.sample_len();
+    let samples = data.chunks_exact(sample_len).map(|sample| {
+        if sample_len == 2 {
+            u16::from_le_bytes([sample[0], sample[1]])
+        } else {
+            sample[0] as u16
+        }
+    });
+    let (chroma_width, chroma_height) = header.chroma_size();
+    let luma_len = header.width as usize * header.height as usize;
+    let chroma_len = chroma_width as usize * chroma_height as usize;
+    let mut values = samples
+        .enumerate()
+        .map(|(i, sample)| header.decode(sample, i >= luma_len));
+    let mut plane = |width: u32, height: u32, len: usize| -> Plane {
+        image::ImageBuffer::from_raw(width, height, values.by_ref().take(len).collect())
+            .expect("Load from raw image error")
+    };
+    let luma = plane(header.width, header.height, luma_len);
+    let cb = plane(chroma_width, chroma_height, chroma_len);
+    let cr = plane(chroma_width, chroma_height, chroma_len);
+
+    let bit_depth = if header.bit_depth > 8 {
+        BitDepth::Sixteen
+    } else {
+        BitDepth::Eight
+    };
+    Ok(Some(YCbCrKernel::from_planes(
+        luma, cb, cr, luma_model, bit_depth,
+    )))
+}
+
+/// Writes `planes` as a frame, the chroma having the size `header` gives
+pub fn write_frame<W: Write>(
+    output: &mut W,
+    header: &Header,
+    planes: &YCbCrKernel,
+) -> std::io::Result<()> {
+    let luma = planes.luma.image.pixels().map(|pixel| (pixel[0], false));
+    let cb = planes.cb.pixels().map(|pixel| (pixel[0], true));
+    let cr = planes.cr.pixels().map(|pixel| (pixel[0], true));
+    let mut data = Vec::with_capacity(header.frame_len());
+    for (value, chroma) in luma.chain(cb).chain(cr) {
+        let sample = header.encode(value, chroma);
+        if header.sample_len() == 2 {
+            data.extend_from_slice(&sample.to_le_bytes());
+        } else {
+            data.push(sample as u8);
+        }
+    }
+    output.write_all(b"FRAME\n")?;
+    output.write_all(&data)
+}
+
+/// Whether `filename` holds a Y4M stream, by its extension or its magic.
+/// Stdin is only peeked at, the bytes stay for the reader.
+pub fn is_y4m(filename: &str) -> bool {
+    if filename == "-" {
+        let stdin = std::io::stdin();
+        let mut stdin = stdin.lock();
+        return stdin
+            .fill_buf()
+            .is_ok_and(|buffer| buffer.starts_with(MAGIC));
+    }
+    if filename.to_lowercase().ends_with(".y4m") {
+        return true;
+    }
+    let mut magic = [0; MAGIC.len()];
+    std::fs::File::open(filename)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| magic == MAGIC)
+}
+
+/// Runs `pipeline` on every frame of the Y4M stream in `input` and writes
+/// them as they come to a stream of the new size in `output`, `-` being stdin
+/// and stdout
+pub fn upscale(input: &str, output: &str, pipeline: &Pipeline) -> std::io::Result<()> {
+    let reader: Box<dyn BufRead> = if input == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(std::io::BufReader::new(std::fs::File::open(input)?))
+    };
+    upscale_stream(reader, image_kernel::create(output)?, pipeline)
+}
+
+/// Runs `pipeline` on every frame read from `reader` and writes them to
+/// `writer`, which is dropped at the end. The YCbCr matrix is the `pipeline`
+/// luma model, BT.601 standing in for the approximation.
+pub fn upscale_stream<R: BufRead, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    pipeline: &Pipeline,
+) -> std::io::Result<()> {
+    let matrix = match pipeline.luma_model {
+        LumaModel::Approximate => LumaModel::Bt601,
+        model => model,
+    };
+
+    let header = read_header(&mut reader)?;
+    let (_, (width, height)) = pipeline.resize.layout(header.width, header.height);
+    let output_header = header.resized(width, height);
+    output_header.write(&mut writer)?;
+    let (chroma_width, chroma_height) = output_header.chroma_size();
+
+    while let Some(mut planes) = read_frame(&mut reader, &header, matrix)? {
+        planes.scale_chroma(pipeline.chroma_filter);
+        let mut kernel = planes.to_rgba();
+        pipeline.run(&mut kernel);
+        kernel.luma_model = matrix;
+        let mut planes = kernel.to_ycbcr();
+        planes.resize_chroma(chroma_width, chroma_height, pipeline.chroma_filter);
+        write_frame(&mut writer, &output_header, &planes)?;
+    }
+    writer.flush()
+}